@@ -1,29 +1,232 @@
 //! Buildkite OIDC token detection.
 
-use crate::DetectionStrategy;
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use reqwest_middleware::ClientWithMiddleware;
+use secrecy::{ExposeSecret as _, SecretString};
+
+use crate::{
+    DetectionStrategy, Provider, TokenRequest,
+    command::{CommandRunner, Invocation},
+    context::{BuildContext, var},
+    http::{ErrorFormat, ResponseExt as _},
+};
+
+const BUILDKITE_AGENT: &str = "buildkite-agent";
+/// Overrides the path to `buildkite-agent`, unless set in [`Options`].
+const BUILDKITE_AGENT_ENV: &str = "AMBIENT_ID_BUILDKITE_AGENT";
+const BUILDKITE_DEFAULT_AGENT_ENDPOINT: &str = "https://agent.buildkite.com/v3";
 
 /// Possible errors during Buildkite OIDC token detection.
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     /// An error occurred while executing the `buildkite-agent` command.
     #[error("failed to obtain OIDC token from `buildkite-agent` CLI")]
-    Execution(#[from] std::io::Error),
+    Execution(#[from] crate::CommandError),
+    /// The `buildkite-agent` command's output wasn't a JWT.
+    #[error("`buildkite-agent` returned something other than a JWT: {preview}")]
+    MalformedToken {
+        /// A redacted preview of the command's output.
+        preview: String,
+    },
+    /// The HTTP request to the Buildkite agent API failed.
+    #[error("failed to obtain OIDC token from the Buildkite agent API")]
+    Request(#[from] crate::HttpError),
+}
+
+/// Options for Buildkite OIDC token requests.
+///
+/// These correspond to the flags of `buildkite-agent oidc request-token`
+/// (or the equivalent agent API fields).
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    agent_path: Option<PathBuf>,
+    lifetime: Option<Duration>,
+    claims: Vec<String>,
+    aws_session_tags: Vec<String>,
+}
+
+impl Options {
+    /// Sets the path to the `buildkite-agent` binary.
+    ///
+    /// This takes precedence over the `AMBIENT_ID_BUILDKITE_AGENT`
+    /// environment variable. If neither is set, `buildkite-agent` is
+    /// looked up on `PATH`.
+    pub fn with_agent_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.agent_path = Some(path.into());
+        self
+    }
+
+    /// Sets the requested lifetime of the token (`--lifetime`).
+    ///
+    /// The lifetime is rounded down to whole seconds. If unset, the
+    /// agent's default lifetime applies. A lifetime set on a
+    /// [`TokenRequest`] takes precedence over this one.
+    pub fn with_lifetime(mut self, lifetime: Duration) -> Self {
+        self.lifetime = Some(lifetime);
+        self
+    }
+
+    /// Sets the optional claims to include in the token (`--claim`).
+    ///
+    /// For example, `organization_id`, `pipeline_id` or `cluster_id`.
+    /// See Buildkite's documentation for the supported claims.
+    pub fn with_claims(mut self, claims: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.claims = claims.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets the claims to map to AWS session tags (`--aws-session-tag`).
+    pub fn with_aws_session_tags(
+        mut self,
+        tags: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.aws_session_tags = tags.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Returns the `buildkite-agent` arguments for a token request.
+    fn cli_args(&self, request: &TokenRequest) -> Vec<String> {
+        let mut args = vec![
+            "oidc".to_string(),
+            "request-token".to_string(),
+            "--audience".to_string(),
+            request.audience().to_string(),
+        ];
+
+        if let Some(lifetime) = request.lifetime().or(self.lifetime) {
+            args.extend(["--lifetime".to_string(), lifetime.as_secs().to_string()]);
+        }
+        for claim in &self.claims {
+            args.extend(["--claim".to_string(), claim.clone()]);
+        }
+        for tag in &self.aws_session_tags {
+            args.extend(["--aws-session-tag".to_string(), tag.clone()]);
+        }
+
+        args
+    }
+
+    /// Returns the agent API request body for a token request.
+    fn api_body(&self, request: &TokenRequest) -> serde_json::Value {
+        let mut body = serde_json::json!({ "audience": request.audience() });
+
+        if let Some(lifetime) = request.lifetime().or(self.lifetime) {
+            body["lifetime"] = lifetime.as_secs().into();
+        }
+        if !self.claims.is_empty() {
+            body["claims"] = self.claims.clone().into();
+        }
+        if !self.aws_session_tags.is_empty() {
+            body["aws_session_tags"] = self.aws_session_tags.clone().into();
+        }
+
+        body
+    }
+}
+
+enum BuildkiteSubstrategy {
+    /// Obtain the token via the `buildkite-agent` CLI.
+    Cli { program: PathBuf },
+    /// Obtain the token directly from the agent API, as the CLI would.
+    ///
+    /// This is used when the CLI isn't available, e.g. within a container
+    /// run by the Docker plugin without `mount-buildkite-agent: true`.
+    AgentApi {
+        client: ClientWithMiddleware,
+        endpoint: String,
+        access_token: SecretString,
+        job_id: String,
+    },
+}
+
+/// The JSON payload returned by the agent API's OIDC token endpoint.
+#[derive(serde::Deserialize)]
+struct TokenResponse {
+    token: String,
+}
+
+/// Returns whether we're running in a Buildkite job.
+///
+/// See: <https://buildkite.com/docs/pipelines/configure/environment-variables#buildkite-environment-variables>
+fn detected() -> bool {
+    std::env::var("BUILDKITE").is_ok_and(|v| v == "true")
+}
+
+/// Returns the build context of the current Buildkite job.
+pub(crate) fn build_context() -> Option<BuildContext> {
+    if !detected() {
+        return None;
+    }
+
+    // Jobs are anchors on their build's page.
+    let job_url = var("BUILDKITE_BUILD_URL").map(|build_url| match var("BUILDKITE_JOB_ID") {
+        Some(job_id) => format!("{build_url}#{job_id}"),
+        None => build_url,
+    });
+
+    Some(BuildContext {
+        repository: var("BUILDKITE_REPO"),
+        git_ref: var("BUILDKITE_TAG").or_else(|| var("BUILDKITE_BRANCH")),
+        commit_sha: var("BUILDKITE_COMMIT"),
+        run_id: var("BUILDKITE_BUILD_ID"),
+        job_url,
+        ..BuildContext::new(Provider::Buildkite)
+    })
 }
 
-pub(crate) struct Buildkite;
+pub(crate) struct Buildkite {
+    options: Options,
+    runner: Arc<dyn CommandRunner>,
+    command_timeout: Duration,
+    substrategy: BuildkiteSubstrategy,
+}
 
 impl DetectionStrategy for Buildkite {
     type Error = Error;
 
-    fn new(_state: &crate::DetectionState) -> Option<Self>
+    const PROVIDER: Provider = Provider::Buildkite;
+
+    async fn new(state: &crate::DetectionState) -> Option<Self>
     where
         Self: Sized,
     {
-        // https://buildkite.com/docs/pipelines/configure/environment-variables#buildkite-environment-variables
-        std::env::var("BUILDKITE")
-            .ok()
-            .filter(|v| v == "true")
-            .map(|_| Buildkite)
+        if !detected() {
+            return None;
+        }
+
+        // Prefer the CLI when it's configured or available. Otherwise, fall back
+        // to the agent API if the job's environment gives us what we need to use it.
+        let substrategy = if let Some(program) = crate::command::configured_program(
+            state.buildkite.agent_path.as_deref(),
+            BUILDKITE_AGENT_ENV,
+        )
+        .or_else(|| crate::command::find_executable(BUILDKITE_AGENT))
+        {
+            BuildkiteSubstrategy::Cli { program }
+        } else if let (Ok(access_token), Ok(job_id)) = (
+            std::env::var("BUILDKITE_AGENT_ACCESS_TOKEN"),
+            std::env::var("BUILDKITE_JOB_ID"),
+        ) {
+            BuildkiteSubstrategy::AgentApi {
+                client: state.client.clone(),
+                endpoint: std::env::var("BUILDKITE_AGENT_ENDPOINT")
+                    .unwrap_or_else(|_| BUILDKITE_DEFAULT_AGENT_ENDPOINT.into()),
+                access_token: access_token.into(),
+                job_id,
+            }
+        } else {
+            BuildkiteSubstrategy::Cli {
+                program: BUILDKITE_AGENT.into(),
+            }
+        };
+
+        Some(Buildkite {
+            options: state.buildkite.clone(),
+            runner: state.runner.clone(),
+            command_timeout: state.command_timeout,
+            substrategy,
+        })
     }
 
     /// On Buildkite, the OIDC token is provided by the `buildkite-agent`
@@ -34,27 +237,124 @@ impl DetectionStrategy for Buildkite {
     /// ```
     ///
     /// The standard output of this command is the ID token on success.
+    /// Any configured [`Options`] are passed as additional flags.
+    ///
+    /// When the tool isn't available, we make the same request that it
+    /// would to the agent API, i.e. `POST /jobs/<job-id>/oidc/tokens`
+    /// authenticated with the job's agent access token.
     async fn detect(&self, audience: &str) -> Result<crate::IdToken, Self::Error> {
-        let output = std::process::Command::new("buildkite-agent")
-            .args(["oidc", "request-token", "--audience", audience])
-            .output()?;
-
-        if !output.status.success() {
-            return Err(Error::Execution(std::io::Error::other(format!(
-                "`buildkite-agent` exited with code {status}: '{stderr}'",
-                status = output.status,
-                stderr = String::from_utf8_lossy(&output.stderr),
-            ))));
-        }
+        self.request(&TokenRequest::new(audience)).await
+    }
+
+    /// A requested lifetime is passed to the tool (or agent API) as
+    /// `--lifetime`, overriding any lifetime in the [`Options`].
+    async fn request(&self, request: &TokenRequest) -> Result<crate::IdToken, Self::Error> {
+        match &self.substrategy {
+            BuildkiteSubstrategy::Cli { program } => {
+                let invocation = Invocation::new(program, self.options.cli_args(request));
+                let stdout = self.runner.run(invocation, self.command_timeout).await?;
+
+                let token = String::from_utf8_lossy(&stdout).trim().to_string();
+                if !crate::jwt::is_well_formed(&token) {
+                    return Err(Error::MalformedToken {
+                        preview: crate::redact::preview(&token),
+                    });
+                }
+                Ok(crate::IdToken(token.into()))
+            }
+            BuildkiteSubstrategy::AgentApi {
+                client,
+                endpoint,
+                access_token,
+                job_id,
+            } => {
+                let resp = client
+                    .post(format!(
+                        "{endpoint}/jobs/{job_id}/oidc/tokens",
+                        endpoint = endpoint.trim_end_matches('/')
+                    ))
+                    .header(
+                        "Authorization",
+                        format!("Token {}", access_token.expose_secret()),
+                    )
+                    .header("Content-Type", "application/json")
+                    .body(
+                        serde_json::to_string(&self.options.api_body(request))
+                            .expect("impossible: JSON serialization failed"),
+                    )
+                    .send()
+                    .await
+                    .map_err(crate::HttpError::from)?
+                    .check(ErrorFormat::Message)
+                    .await?
+                    .json::<TokenResponse>()
+                    .await
+                    .map_err(crate::HttpError::from)?;
 
-        let token = String::from_utf8_lossy(&output.stdout).trim().to_string();
-        Ok(crate::IdToken(token.into()))
+                Ok(crate::IdToken(resp.token.into()))
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{DetectionStrategy as _, buildkite::Buildkite, tests::EnvScope};
+    use wiremock::{
+        Mock, MockServer,
+        matchers::{body_json, header, method, path},
+    };
+
+    use std::{path::Path, sync::Arc, time::Duration};
+
+    use crate::{
+        DetectionStrategy as _, TokenRequest,
+        buildkite::{Buildkite, BuildkiteSubstrategy, Options},
+        command::{
+            Invocation,
+            tests::{ScriptedRunner, non_zero_exit, touch_executable},
+        },
+        jwt::tests::fake_jwt,
+        tests::EnvScope,
+    };
+
+    /// Sets up a Buildkite job environment with `buildkite-agent` on `PATH`,
+    /// and returns a detector that runs it with the given runner.
+    async fn cli_detector(
+        scope: &mut EnvScope,
+        bin: &std::path::Path,
+        runner: Arc<ScriptedRunner>,
+        options: Options,
+    ) -> Buildkite {
+        touch_executable(bin, "buildkite-agent");
+        scope.setenv("BUILDKITE", "true");
+        scope.setenv("PATH", bin.to_str().unwrap());
+        scope.unsetenv("AMBIENT_ID_BUILDKITE_AGENT");
+
+        let state = crate::DetectionState {
+            runner,
+            buildkite: options,
+            ..Default::default()
+        };
+        let detector = Buildkite::new(&state)
+            .await
+            .expect("should detect Buildkite");
+        assert!(matches!(
+            detector.substrategy,
+            BuildkiteSubstrategy::Cli { .. }
+        ));
+        detector
+    }
+
+    /// Sets up a Buildkite job environment that can use the agent API,
+    /// with `buildkite-agent` absent from `PATH`.
+    fn agent_api_env(scope: &mut EnvScope, path: &std::path::Path, endpoint: &str) {
+        scope.setenv("BUILDKITE", "true");
+        scope.setenv("PATH", path.to_str().unwrap());
+        scope.unsetenv("AMBIENT_ID_BUILDKITE_AGENT");
+        scope.setenv("BUILDKITE_AGENT_ENDPOINT", endpoint);
+        scope.setenv("BUILDKITE_AGENT_ACCESS_TOKEN", "test-access-token");
+        scope.setenv("BUILDKITE_JOB_ID", "test-job-id");
+    }
 
     #[tokio::test]
     async fn test_not_detected() {
@@ -62,7 +362,7 @@ mod tests {
         scope.unsetenv("BUILDKITE");
 
         let state = Default::default();
-        assert!(Buildkite::new(&state).is_none());
+        assert!(Buildkite::new(&state).await.is_none());
     }
 
     #[tokio::test]
@@ -71,7 +371,7 @@ mod tests {
         scope.setenv("BUILDKITE", "true");
 
         let state = Default::default();
-        assert!(Buildkite::new(&state).is_some());
+        assert!(Buildkite::new(&state).await.is_some());
     }
 
     /// Happy path for Buildkite OIDC token detection.
@@ -80,7 +380,9 @@ mod tests {
     async fn test_1p_detection_ok() {
         let _ = EnvScope::new();
         let state = Default::default();
-        let detector = Buildkite::new(&state).expect("should detect Buildkite");
+        let detector = Buildkite::new(&state)
+            .await
+            .expect("should detect Buildkite");
         let token = detector
             .detect("test_1p_detection_ok")
             .await
@@ -88,4 +390,376 @@ mod tests {
 
         assert!(token.reveal().starts_with("eyJ"));
     }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn test_detected_cli() {
+        let mut scope = EnvScope::new();
+        let bin = tempfile::tempdir().unwrap();
+        touch_executable(bin.path(), "buildkite-agent");
+
+        // The CLI is preferred, even when the agent API is usable.
+        agent_api_env(&mut scope, bin.path(), "https://agent.example.com/v3");
+
+        let state = Default::default();
+        let detector = Buildkite::new(&state)
+            .await
+            .expect("should detect Buildkite");
+        assert!(matches!(
+            detector.substrategy,
+            BuildkiteSubstrategy::Cli { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_detected_agent_api() {
+        let mut scope = EnvScope::new();
+        let empty = tempfile::tempdir().unwrap();
+        agent_api_env(&mut scope, empty.path(), "https://agent.example.com/v3");
+
+        let state = Default::default();
+        let detector = Buildkite::new(&state)
+            .await
+            .expect("should detect Buildkite");
+        assert!(matches!(
+            detector.substrategy,
+            BuildkiteSubstrategy::AgentApi { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_detected_no_cli_no_agent_api() {
+        let mut scope = EnvScope::new();
+        let empty = tempfile::tempdir().unwrap();
+        agent_api_env(&mut scope, empty.path(), "https://agent.example.com/v3");
+        scope.unsetenv("BUILDKITE_AGENT_ACCESS_TOKEN");
+
+        // With neither available, we fall back to the CLI so that its
+        // absence is reported.
+        let state = Default::default();
+        let detector = Buildkite::new(&state)
+            .await
+            .expect("should detect Buildkite");
+        assert!(matches!(
+            detector.substrategy,
+            BuildkiteSubstrategy::Cli { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_detected_configured_agent_path() {
+        let mut scope = EnvScope::new();
+        let empty = tempfile::tempdir().unwrap();
+        agent_api_env(&mut scope, empty.path(), "https://agent.example.com/v3");
+
+        // A configured path is used even though it isn't on `PATH`,
+        // and even though the agent API is usable.
+        scope.setenv(
+            "AMBIENT_ID_BUILDKITE_AGENT",
+            "/opt/buildkite/bin/buildkite-agent",
+        );
+        let state = Default::default();
+        let detector = Buildkite::new(&state)
+            .await
+            .expect("should detect Buildkite");
+        assert!(matches!(
+            detector.substrategy,
+            BuildkiteSubstrategy::Cli { program } if program == Path::new("/opt/buildkite/bin/buildkite-agent")
+        ));
+
+        // The options take precedence over the environment.
+        let state = crate::DetectionState {
+            buildkite: Options::default().with_agent_path("/usr/local/bin/buildkite-agent"),
+            ..Default::default()
+        };
+        let detector = Buildkite::new(&state)
+            .await
+            .expect("should detect Buildkite");
+        assert!(matches!(
+            detector.substrategy,
+            BuildkiteSubstrategy::Cli { program } if program == Path::new("/usr/local/bin/buildkite-agent")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_agent_api_ok() {
+        let mut scope = EnvScope::new();
+        let server = MockServer::start().await;
+        let empty = tempfile::tempdir().unwrap();
+        agent_api_env(&mut scope, empty.path(), &format!("{}/v3/", server.uri()));
+
+        Mock::given(method("POST"))
+            .and(path("/v3/jobs/test-job-id/oidc/tokens"))
+            .and(header("Authorization", "Token test-access-token"))
+            .and(body_json(
+                serde_json::json!({ "audience": "test_agent_api_ok" }),
+            ))
+            .respond_with(
+                wiremock::ResponseTemplate::new(201).set_body_json(serde_json::json!({
+                    "token": "test-agent-api-token"
+                })),
+            )
+            .mount(&server)
+            .await;
+
+        let state = Default::default();
+        let detector = Buildkite::new(&state)
+            .await
+            .expect("should detect Buildkite");
+        let token = detector
+            .detect("test_agent_api_ok")
+            .await
+            .expect("should fetch token");
+
+        assert_eq!(token.reveal(), "test-agent-api-token");
+    }
+
+    #[tokio::test]
+    async fn test_agent_api_error_code() {
+        let mut scope = EnvScope::new();
+        let server = MockServer::start().await;
+        let empty = tempfile::tempdir().unwrap();
+        agent_api_env(&mut scope, empty.path(), &format!("{}/v3", server.uri()));
+
+        Mock::given(method("POST"))
+            .and(path("/v3/jobs/test-job-id/oidc/tokens"))
+            .respond_with(wiremock::ResponseTemplate::new(401))
+            .mount(&server)
+            .await;
+
+        let state = Default::default();
+        let detector = Buildkite::new(&state)
+            .await
+            .expect("should detect Buildkite");
+        assert!(matches!(
+            detector.detect("test_agent_api_error_code").await,
+            Err(super::Error::Request(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_agent_api_invalid_response() {
+        let mut scope = EnvScope::new();
+        let server = MockServer::start().await;
+        let empty = tempfile::tempdir().unwrap();
+        agent_api_env(&mut scope, empty.path(), &format!("{}/v3", server.uri()));
+
+        Mock::given(method("POST"))
+            .and(path("/v3/jobs/test-job-id/oidc/tokens"))
+            .respond_with(
+                wiremock::ResponseTemplate::new(201).set_body_json(serde_json::json!({
+                    "bogus": "response"
+                })),
+            )
+            .mount(&server)
+            .await;
+
+        let state = Default::default();
+        let detector = Buildkite::new(&state)
+            .await
+            .expect("should detect Buildkite");
+        assert!(matches!(
+            detector.detect("test_agent_api_invalid_response").await,
+            Err(super::Error::Request(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_agent_api_options() {
+        let mut scope = EnvScope::new();
+        let server = MockServer::start().await;
+        let empty = tempfile::tempdir().unwrap();
+        agent_api_env(&mut scope, empty.path(), &format!("{}/v3", server.uri()));
+
+        Mock::given(method("POST"))
+            .and(path("/v3/jobs/test-job-id/oidc/tokens"))
+            .and(body_json(serde_json::json!({
+                "audience": "test_agent_api_options",
+                "lifetime": 300,
+                "claims": ["organization_id"],
+            })))
+            .respond_with(
+                wiremock::ResponseTemplate::new(201).set_body_json(serde_json::json!({
+                    "token": "test-agent-api-token"
+                })),
+            )
+            .mount(&server)
+            .await;
+
+        let state = crate::DetectionState {
+            buildkite: Options::default()
+                .with_lifetime(Duration::from_secs(300))
+                .with_claims(["organization_id"]),
+            ..Default::default()
+        };
+        let detector = Buildkite::new(&state)
+            .await
+            .expect("should detect Buildkite");
+        let token = detector
+            .detect("test_agent_api_options")
+            .await
+            .expect("should fetch token");
+
+        assert_eq!(token.reveal(), "test-agent-api-token");
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn test_cli_ok() {
+        let mut scope = EnvScope::new();
+        let bin = tempfile::tempdir().unwrap();
+        let jwt = fake_jwt(serde_json::json!({"aud": "bupkis"}));
+        let runner = Arc::new(ScriptedRunner::new([Ok(format!("{jwt}\n").into_bytes())]));
+        let detector = cli_detector(
+            &mut scope,
+            bin.path(),
+            runner.clone(),
+            Options::default().with_claims(["cluster_id"]),
+        )
+        .await;
+
+        let token = detector.detect("bupkis").await.expect("should fetch token");
+        assert_eq!(token.reveal(), jwt);
+        assert_eq!(
+            runner.invocations(),
+            [Invocation::new(
+                bin.path().join("buildkite-agent"),
+                [
+                    "oidc",
+                    "request-token",
+                    "--audience",
+                    "bupkis",
+                    "--claim",
+                    "cluster_id"
+                ]
+                .map(String::from)
+                .to_vec()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn test_cli_non_zero_exit() {
+        let mut scope = EnvScope::new();
+        let bin = tempfile::tempdir().unwrap();
+        let runner = Arc::new(ScriptedRunner::new([Err(non_zero_exit(
+            "buildkite-agent",
+            1,
+            "fatal: failed to get OIDC token",
+        ))]));
+        let detector = cli_detector(&mut scope, bin.path(), runner, Default::default()).await;
+
+        let Err(err) = detector.detect("bupkis").await else {
+            panic!("expected an error");
+        };
+        assert_eq!(
+            err.to_string(),
+            "failed to obtain OIDC token from `buildkite-agent` CLI"
+        );
+        assert_eq!(
+            std::error::Error::source(&err).unwrap().to_string(),
+            "`buildkite-agent` exited with exit status: 1: 'fatal: failed to get OIDC token'"
+        );
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn test_cli_malformed_token() {
+        let mut scope = EnvScope::new();
+        let bin = tempfile::tempdir().unwrap();
+        let runner = Arc::new(ScriptedRunner::new([
+            Ok(b"A new version of buildkite-agent is available!\n".to_vec()),
+            Ok(vec![]),
+        ]));
+        let detector = cli_detector(&mut scope, bin.path(), runner, Default::default()).await;
+
+        let Err(err) = detector.detect("bupkis").await else {
+            panic!("expected an error");
+        };
+        assert_eq!(
+            err.to_string(),
+            "`buildkite-agent` returned something other than a JWT: \
+             A new version of buildkite-agent is available!"
+        );
+
+        let Err(err) = detector.detect("bupkis").await else {
+            panic!("expected an error");
+        };
+        assert!(matches!(err, super::Error::MalformedToken { preview } if preview == "<empty>"));
+    }
+
+    #[test]
+    fn test_cli_args() {
+        assert_eq!(
+            Options::default().cli_args(&TokenRequest::new("bupkis")),
+            ["oidc", "request-token", "--audience", "bupkis"]
+        );
+
+        let options = Options::default()
+            .with_lifetime(Duration::from_millis(90_500))
+            .with_claims(["organization_id", "cluster_id"])
+            .with_aws_session_tags(["organization_slug"]);
+        assert_eq!(
+            options.cli_args(&TokenRequest::new("bupkis")),
+            [
+                "oidc",
+                "request-token",
+                "--audience",
+                "bupkis",
+                "--lifetime",
+                "90",
+                "--claim",
+                "organization_id",
+                "--claim",
+                "cluster_id",
+                "--aws-session-tag",
+                "organization_slug",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_api_body() {
+        assert_eq!(
+            Options::default().api_body(&TokenRequest::new("bupkis")),
+            serde_json::json!({ "audience": "bupkis" })
+        );
+
+        let options = Options::default()
+            .with_lifetime(Duration::from_secs(60))
+            .with_claims(["organization_id"])
+            .with_aws_session_tags(["organization_slug", "pipeline_slug"]);
+        assert_eq!(
+            options.api_body(&TokenRequest::new("bupkis")),
+            serde_json::json!({
+                "audience": "bupkis",
+                "lifetime": 60,
+                "claims": ["organization_id"],
+                "aws_session_tags": ["organization_slug", "pipeline_slug"],
+            })
+        );
+    }
+
+    #[test]
+    fn test_request_lifetime_overrides_options() {
+        let options = Options::default().with_lifetime(Duration::from_secs(60));
+        let request = TokenRequest::new("bupkis").with_lifetime(Duration::from_secs(300));
+
+        assert_eq!(
+            options.cli_args(&request),
+            [
+                "oidc",
+                "request-token",
+                "--audience",
+                "bupkis",
+                "--lifetime",
+                "300"
+            ]
+        );
+        assert_eq!(
+            options.api_body(&request),
+            serde_json::json!({ "audience": "bupkis", "lifetime": 300 })
+        );
+    }
 }
@@ -0,0 +1,232 @@
+//! An opt-in, in-process cache of detected tokens.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use crate::{IdToken, Provider, TokenRequest};
+
+/// Identifies tokens that are interchangeable: those from the same
+/// provider, for the same request.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Key {
+    provider: Provider,
+    audience: String,
+    lifetime: Option<Duration>,
+    /// The requested claims, serialized (since JSON values aren't `Hash`).
+    claims: String,
+}
+
+impl Key {
+    fn new(provider: Provider, request: &TokenRequest) -> Self {
+        Key {
+            provider,
+            audience: request.audience().into(),
+            lifetime: request.lifetime(),
+            claims: serde_json::Value::Object(request.claims().clone()).to_string(),
+        }
+    }
+}
+
+/// A cached token, with its expiry.
+struct Entry {
+    token: IdToken,
+    expires_at: SystemTime,
+}
+
+/// The tokens for one key. Its lock is held while a token is requested,
+/// so that concurrent callers wait for that request instead of making
+/// their own.
+type Slot = Arc<tokio::sync::Mutex<Option<Entry>>>;
+
+/// Caches tokens until shortly before they expire.
+pub(crate) struct Cache {
+    margin: Duration,
+    slots: Mutex<HashMap<Key, Slot>>,
+}
+
+impl Cache {
+    /// Creates a cache that reuses tokens until `margin` before they expire.
+    pub(crate) fn new(margin: Duration) -> Self {
+        Cache {
+            margin,
+            slots: Default::default(),
+        }
+    }
+
+    /// Returns a cached token for `request` from `provider` if there's one
+    /// that won't expire within the margin, or otherwise obtains a new one
+    /// with `fetch` and caches it.
+    ///
+    /// Tokens without an `exp` claim (or that aren't JWTs) are never cached.
+    pub(crate) async fn get_or_fetch<F, E>(
+        &self,
+        provider: Provider,
+        request: &TokenRequest,
+        fetch: F,
+    ) -> Result<IdToken, E>
+    where
+        F: AsyncFnOnce() -> Result<IdToken, E>,
+    {
+        let slot = self
+            .slots
+            .lock()
+            .expect("impossible: cache lock poisoned")
+            .entry(Key::new(provider, request))
+            .or_default()
+            .clone();
+
+        let mut entry = slot.lock().await;
+        if let Some(cached) = entry.as_ref() {
+            // A margin too large to represent means every token is expired.
+            let fresh = SystemTime::now()
+                .checked_add(self.margin)
+                .is_some_and(|deadline| deadline < cached.expires_at);
+            if fresh {
+                return Ok(IdToken(cached.token.0.clone()));
+            }
+        }
+
+        let token = fetch().await?;
+        *entry = expires_at(&token).map(|expires_at| Entry {
+            token: IdToken(token.0.clone()),
+            expires_at,
+        });
+
+        Ok(token)
+    }
+}
+
+/// Returns when a token expires, per its `exp` claim.
+fn expires_at(token: &IdToken) -> Option<SystemTime> {
+    let exp = crate::jwt::decode_claims(token.reveal())?
+        .get("exp")?
+        .as_u64()?;
+    SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(exp))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::{Duration, SystemTime},
+    };
+
+    use serde_json::json;
+
+    use crate::{IdToken, Provider, TokenRequest, jwt::tests::fake_jwt};
+
+    use super::Cache;
+
+    /// Returns a JWT that expires `secs` seconds from now.
+    fn jwt_expiring_in(secs: u64) -> String {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        fake_jwt(json!({ "exp": now.as_secs() + secs }))
+    }
+
+    /// Fetches a token through `cache`, counting calls to the fetcher.
+    async fn fetch(
+        cache: &Cache,
+        request: &TokenRequest,
+        token: &str,
+        calls: &AtomicUsize,
+    ) -> IdToken {
+        cache
+            .get_or_fetch(Provider::GitHubActions, request, async || {
+                calls.fetch_add(1, Ordering::SeqCst);
+                tokio::task::yield_now().await;
+                Ok::<_, ()>(IdToken(token.to_string().into()))
+            })
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_reuse() {
+        let cache = Cache::new(Duration::from_secs(60));
+        let calls = AtomicUsize::new(0);
+        let token = jwt_expiring_in(600);
+        let request = TokenRequest::new("bupkis");
+
+        for _ in 0..3 {
+            let cached = fetch(&cache, &request, &token, &calls).await;
+            assert_eq!(cached.reveal(), token);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Different requests are cached separately.
+        fetch(&cache, &TokenRequest::new("other"), &token, &calls).await;
+        let request = TokenRequest::new("bupkis").with_claim("foo", "bar");
+        fetch(&cache, &request, &token, &calls).await;
+        fetch(&cache, &request, &token, &calls).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_expiry_margin() {
+        let cache = Cache::new(Duration::from_secs(60));
+        let calls = AtomicUsize::new(0);
+        let request = TokenRequest::new("bupkis");
+
+        // Expires within the margin, so it's refetched every time.
+        let token = jwt_expiring_in(30);
+        fetch(&cache, &request, &token, &calls).await;
+        fetch(&cache, &request, &token, &calls).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Tokens without an expiry are never cached.
+        fetch(&cache, &request, "sometoken", &calls).await;
+        fetch(&cache, &request, "sometoken", &calls).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        // A margin too large to add to the current time treats every token
+        // as expired, rather than panicking.
+        let cache = Cache::new(Duration::MAX);
+        let token = jwt_expiring_in(600);
+        fetch(&cache, &request, &token, &calls).await;
+        fetch(&cache, &request, &token, &calls).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 6);
+    }
+
+    #[tokio::test]
+    async fn test_coalesce() {
+        let cache = Cache::new(Duration::from_secs(60));
+        let calls = AtomicUsize::new(0);
+        let token = jwt_expiring_in(600);
+        let request = TokenRequest::new("bupkis");
+
+        let (a, b, c) = tokio::join!(
+            fetch(&cache, &request, &token, &calls),
+            fetch(&cache, &request, &token, &calls),
+            fetch(&cache, &request, &token, &calls),
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        for cached in [a, b, c] {
+            assert_eq!(cached.reveal(), token);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_errors_not_cached() {
+        let cache = Cache::new(Duration::from_secs(60));
+        let request = TokenRequest::new("bupkis");
+
+        let result = cache
+            .get_or_fetch(Provider::GitHubActions, &request, async || Err("nope"))
+            .await;
+        assert!(matches!(result, Err("nope")));
+
+        let token = jwt_expiring_in(600);
+        let cached = cache
+            .get_or_fetch(Provider::GitHubActions, &request, async || {
+                Ok::<_, ()>(IdToken(token.clone().into()))
+            })
+            .await
+            .unwrap();
+        assert_eq!(cached.reveal(), token);
+    }
+}
@@ -1,31 +1,148 @@
 //! CircleCI OIDC token detection.
 
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
 use serde_json::json;
 
-use crate::DetectionStrategy;
+use crate::{
+    DetectionStrategy, Provider, TokenRequest,
+    command::{CommandRunner, Invocation},
+    context::{BuildContext, var},
+};
+
+const CIRCLECI_CLI: &str = "circleci";
+/// Overrides the path to `circleci`, unless set in [`Options`].
+const CIRCLECI_CLI_ENV: &str = "AMBIENT_ID_CIRCLECI_CLI";
+
+/// Pre-issued token variables, in order of preference.
+///
+/// These are always issued by the organization issuer, for the
+/// organization's default audience (i.e. its ID).
+const CIRCLECI_TOKEN_VARIABLES: &[&str] = &["CIRCLE_OIDC_TOKEN_V2", "CIRCLE_OIDC_TOKEN"];
 
 /// Possible errors during BuildKite OIDC token detection.
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     /// An error occurred while executing the `circleci` command.
     #[error("failed to obtain OIDC token from `circleci` CLI")]
-    Execution(#[from] std::io::Error),
+    Execution(#[from] crate::CommandError),
+    /// The `circleci` command's output wasn't a JWT.
+    #[error("`circleci` returned something other than a JWT: {preview}")]
+    MalformedToken {
+        /// A redacted preview of the command's output.
+        preview: String,
+    },
+    /// A custom claim would override the token's audience.
+    #[error("custom claims can't include `aud`; use the requested audience instead")]
+    AudienceClaim,
+}
+
+/// The issuer of CircleCI OIDC tokens.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Issuer {
+    /// The root issuer, `https://oidc.circleci.com`.
+    #[default]
+    Root,
+    /// The per-organization issuer, `https://oidc.circleci.com/org/<org-id>`.
+    ///
+    /// With this issuer, requests for the organization's default audience
+    /// are served from the pre-issued `CIRCLE_OIDC_TOKEN_V2` or
+    /// `CIRCLE_OIDC_TOKEN` variables, without requiring the `circleci` CLI.
+    Organization,
+}
+
+/// Options for CircleCI OIDC token requests.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    cli_path: Option<PathBuf>,
+    issuer: Issuer,
 }
 
-pub(crate) struct CircleCI;
+impl Options {
+    /// Sets the path to the `circleci` binary.
+    ///
+    /// This takes precedence over the `AMBIENT_ID_CIRCLECI_CLI`
+    /// environment variable. If neither is set, `circleci` is
+    /// looked up on `PATH`.
+    pub fn with_cli_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.cli_path = Some(path.into());
+        self
+    }
+
+    /// Sets the issuer to request tokens from.
+    pub fn with_issuer(mut self, issuer: Issuer) -> Self {
+        self.issuer = issuer;
+        self
+    }
+
+    /// Returns the `circleci` arguments for a token request.
+    fn cli_args(&self, request: &TokenRequest) -> Result<Vec<String>, Error> {
+        if request.claims().contains_key("aud") {
+            return Err(Error::AudienceClaim);
+        }
+
+        let mut args = vec!["run".to_string(), "oidc".to_string(), "get".to_string()];
+
+        if self.issuer == Issuer::Root {
+            args.push("--root-issuer".to_string());
+        }
+
+        let mut all_claims = request.claims().clone();
+        all_claims.insert("aud".to_string(), json!(request.audience()));
+        args.extend([
+            "--claims".to_string(),
+            serde_json::Value::Object(all_claims).to_string(),
+        ]);
+
+        Ok(args)
+    }
+}
+
+/// Returns whether we're running in a CircleCI job.
+///
+/// See: <https://circleci.com/docs/reference/variables/#built-in-environment-variables>
+fn detected() -> bool {
+    std::env::var("CIRCLECI").is_ok_and(|v| v == "true")
+}
+
+/// Returns the build context of the current CircleCI job.
+pub(crate) fn build_context() -> Option<BuildContext> {
+    detected().then(|| BuildContext {
+        repository: var("CIRCLE_REPOSITORY_URL"),
+        git_ref: var("CIRCLE_TAG").or_else(|| var("CIRCLE_BRANCH")),
+        commit_sha: var("CIRCLE_SHA1"),
+        run_id: var("CIRCLE_WORKFLOW_ID"),
+        job_url: var("CIRCLE_BUILD_URL"),
+        ..BuildContext::new(Provider::CircleCI)
+    })
+}
+
+pub(crate) struct CircleCI {
+    options: Options,
+    program: PathBuf,
+    runner: Arc<dyn CommandRunner>,
+    command_timeout: Duration,
+}
 
 impl DetectionStrategy for CircleCI {
     type Error = Error;
 
-    fn new(_state: &crate::DetectionState) -> Option<Self>
+    const PROVIDER: Provider = Provider::CircleCI;
+
+    async fn new(state: &crate::DetectionState) -> Option<Self>
     where
         Self: Sized,
     {
-        // https://circleci.com/docs/reference/variables/#built-in-environment-variables
-        std::env::var("CIRCLECI")
-            .ok()
-            .filter(|v| v == "true")
-            .map(|_| CircleCI)
+        detected().then(|| CircleCI {
+            options: state.circleci.clone(),
+            program: crate::command::configured_program(
+                state.circleci.cli_path.as_deref(),
+                CIRCLECI_CLI_ENV,
+            )
+            .unwrap_or_else(|| CIRCLECI_CLI.into()),
+            runner: state.runner.clone(),
+            command_timeout: state.command_timeout,
+        })
     }
 
     /// On CircleCI, the OIDC token is provided by the `circleci` tool.
@@ -36,37 +153,60 @@ impl DetectionStrategy for CircleCI {
     /// ```
     ///
     /// The standard output of this command is the ID token on success.
+    /// With the [`Organization`](Issuer::Organization) issuer, `--root-issuer`
+    /// is omitted, and a pre-issued token is used instead when it's already
+    /// for the requested audience.
     async fn detect(&self, audience: &str) -> Result<crate::IdToken, Self::Error> {
-        let output = std::process::Command::new("circleci")
-            .args([
-                "run",
-                "oidc",
-                "get",
-                "--root-issuer",
-                "--claims",
-                &json!({
-                    "aud": audience
-                })
-                .to_string(),
-            ])
-            .output()?;
-
-        if !output.status.success() {
-            return Err(Error::Execution(std::io::Error::other(format!(
-                "`circleci` exited with code {status}: '{stderr}'",
-                status = output.status,
-                stderr = String::from_utf8_lossy(&output.stderr),
-            ))));
+        self.request(&TokenRequest::new(audience)).await
+    }
+
+    /// Any custom claims are passed to the `circleci` tool alongside
+    /// the audience, in `--claims`.
+    async fn request(&self, request: &TokenRequest) -> Result<crate::IdToken, Self::Error> {
+        let args = self.options.cli_args(request)?;
+        let audience = request.audience();
+
+        // Pre-issued tokens never have custom claims.
+        if self.options.issuer == Issuer::Organization && request.claims().is_empty() {
+            let preissued = CIRCLECI_TOKEN_VARIABLES
+                .iter()
+                .filter_map(|var| std::env::var(var).ok())
+                .find(|token| crate::jwt::has_audience(token, audience));
+
+            if let Some(token) = preissued {
+                return Ok(crate::IdToken(token.into()));
+            }
         }
 
-        let token = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        let invocation = Invocation::new(&self.program, args);
+        let stdout = self.runner.run(invocation, self.command_timeout).await?;
+
+        let token = String::from_utf8_lossy(&stdout).trim().to_string();
+        if !crate::jwt::is_well_formed(&token) {
+            return Err(Error::MalformedToken {
+                preview: crate::redact::preview(&token),
+            });
+        }
         Ok(crate::IdToken(token.into()))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{DetectionStrategy as _, circleci::CircleCI, tests::EnvScope};
+    use serde_json::{Value, json};
+
+    use std::{path::PathBuf, sync::Arc};
+
+    use crate::{
+        DetectionStrategy as _, TokenRequest,
+        circleci::{CircleCI, Issuer, Options},
+        command::{
+            Invocation,
+            tests::{ScriptedRunner, non_zero_exit},
+        },
+        jwt::tests::fake_jwt,
+        tests::EnvScope,
+    };
 
     #[tokio::test]
     async fn test_not_detected() {
@@ -74,7 +214,7 @@ mod tests {
         scope.unsetenv("CIRCLECI");
 
         let state = Default::default();
-        assert!(CircleCI::new(&state).is_none());
+        assert!(CircleCI::new(&state).await.is_none());
     }
 
     #[tokio::test]
@@ -83,7 +223,7 @@ mod tests {
         scope.setenv("CIRCLECI", "true");
 
         let state = Default::default();
-        assert!(CircleCI::new(&state).is_some());
+        assert!(CircleCI::new(&state).await.is_some());
     }
 
     /// Happy path for CircleCI OIDC token detection.
@@ -92,7 +232,7 @@ mod tests {
     async fn test_1p_detection_ok() {
         let _ = EnvScope::new();
         let state = Default::default();
-        let detector = CircleCI::new(&state).expect("should detect CircleCI");
+        let detector = CircleCI::new(&state).await.expect("should detect CircleCI");
         let token = detector
             .detect("test_1p_detection_ok")
             .await
@@ -100,4 +240,297 @@ mod tests {
 
         assert!(token.reveal().starts_with("eyJ"));
     }
+
+    #[tokio::test]
+    async fn test_cli_ok() {
+        let mut scope = EnvScope::new();
+        scope.setenv("CIRCLECI", "true");
+        scope.unsetenv("AMBIENT_ID_CIRCLECI_CLI");
+
+        let jwt = fake_jwt(json!({"aud": "bupkis", "env": "prod"}));
+        let runner = Arc::new(ScriptedRunner::new([Ok(format!("  {jwt}\n").into_bytes())]));
+        let state = crate::DetectionState {
+            runner: runner.clone(),
+            ..Default::default()
+        };
+        let detector = CircleCI::new(&state).await.expect("should detect CircleCI");
+
+        let token = detector
+            .request(&TokenRequest::new("bupkis").with_claim("env", "prod"))
+            .await
+            .expect("should fetch token");
+        assert_eq!(token.reveal(), jwt);
+        assert_eq!(
+            runner.invocations(),
+            [Invocation::new(
+                "circleci",
+                [
+                    "run",
+                    "oidc",
+                    "get",
+                    "--root-issuer",
+                    "--claims",
+                    r#"{"aud":"bupkis","env":"prod"}"#
+                ]
+                .map(String::from)
+                .to_vec()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cli_configured_path() {
+        let mut scope = EnvScope::new();
+        scope.setenv("CIRCLECI", "true");
+        scope.setenv("AMBIENT_ID_CIRCLECI_CLI", "/opt/circleci/bin/circleci");
+
+        let jwt = fake_jwt(json!({"aud": "bupkis"}));
+        let runner = Arc::new(ScriptedRunner::new([
+            Ok(jwt.clone().into_bytes()),
+            Ok(jwt.into_bytes()),
+        ]));
+
+        let state = crate::DetectionState {
+            runner: runner.clone(),
+            ..Default::default()
+        };
+        let detector = CircleCI::new(&state).await.expect("should detect CircleCI");
+        detector.detect("bupkis").await.expect("should fetch token");
+
+        // The options take precedence over the environment.
+        let state = crate::DetectionState {
+            runner: runner.clone(),
+            circleci: Options::default().with_cli_path("/usr/local/bin/circleci"),
+            ..Default::default()
+        };
+        let detector = CircleCI::new(&state).await.expect("should detect CircleCI");
+        detector.detect("bupkis").await.expect("should fetch token");
+
+        let programs = runner
+            .invocations()
+            .into_iter()
+            .map(|invocation| invocation.program)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            programs,
+            [
+                PathBuf::from("/opt/circleci/bin/circleci"),
+                PathBuf::from("/usr/local/bin/circleci")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn test_cli_non_zero_exit() {
+        let mut scope = EnvScope::new();
+        scope.setenv("CIRCLECI", "true");
+
+        let runner = Arc::new(ScriptedRunner::new([Err(non_zero_exit(
+            "circleci",
+            2,
+            "Error: not running in a CircleCI job",
+        ))]));
+        let state = crate::DetectionState {
+            runner,
+            ..Default::default()
+        };
+        let detector = CircleCI::new(&state).await.expect("should detect CircleCI");
+
+        let Err(err) = detector.detect("bupkis").await else {
+            panic!("expected an error");
+        };
+        assert_eq!(
+            err.to_string(),
+            "failed to obtain OIDC token from `circleci` CLI"
+        );
+        assert_eq!(
+            std::error::Error::source(&err).unwrap().to_string(),
+            "`circleci` exited with exit status: 2: 'Error: not running in a CircleCI job'"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cli_malformed_token() {
+        let mut scope = EnvScope::new();
+        scope.setenv("CIRCLECI", "true");
+
+        // A truncated token: its (partial) contents must not leak into the error.
+        let jwt = fake_jwt(json!({"aud": "bupkis"}));
+        let truncated = jwt.rsplit_once('.').unwrap().0;
+        let runner = Arc::new(ScriptedRunner::new([Ok(truncated.as_bytes().to_vec())]));
+        let state = crate::DetectionState {
+            runner,
+            ..Default::default()
+        };
+        let detector = CircleCI::new(&state).await.expect("should detect CircleCI");
+
+        let Err(err) = detector.detect("bupkis").await else {
+            panic!("expected an error");
+        };
+        assert_eq!(
+            err.to_string(),
+            "`circleci` returned something other than a JWT: [REDACTED]"
+        );
+    }
+
+    #[test]
+    fn test_cli_args() {
+        let request = TokenRequest::new("bupkis");
+        assert_eq!(
+            Options::default().cli_args(&request).unwrap(),
+            [
+                "run",
+                "oidc",
+                "get",
+                "--root-issuer",
+                "--claims",
+                r#"{"aud":"bupkis"}"#
+            ]
+        );
+
+        assert_eq!(
+            Options::default()
+                .with_issuer(Issuer::Organization)
+                .cli_args(&request)
+                .unwrap(),
+            ["run", "oidc", "get", "--claims", r#"{"aud":"bupkis"}"#]
+        );
+    }
+
+    #[test]
+    fn test_cli_args_custom_claims() {
+        let request = TokenRequest::new("bupkis")
+            .with_claim("env", "prod")
+            .with_claim("nested", json!({"a": [1, 2]}));
+
+        let args = Options::default().cli_args(&request).unwrap();
+        assert_eq!(args[..4], ["run", "oidc", "get", "--root-issuer"]);
+        assert_eq!(args[4], "--claims");
+        assert_eq!(
+            serde_json::from_str::<Value>(&args[5]).unwrap(),
+            json!({"aud": "bupkis", "env": "prod", "nested": {"a": [1, 2]}})
+        );
+
+        let request = TokenRequest::new("bupkis").with_claim("aud", "other");
+        assert!(matches!(
+            Options::default().cli_args(&request),
+            Err(super::Error::AudienceClaim)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_organization_custom_claims_skip_preissued_token() {
+        let mut scope = EnvScope::new();
+        let empty = tempfile::tempdir().unwrap();
+
+        scope.setenv("CIRCLECI", "true");
+        scope.setenv("PATH", empty.path().to_str().unwrap());
+        scope.setenv(
+            "CIRCLE_OIDC_TOKEN_V2",
+            &fake_jwt(json!({"aud": "test-org-id"})),
+        );
+
+        let state = crate::DetectionState {
+            circleci: Options::default().with_issuer(Issuer::Organization),
+            ..Default::default()
+        };
+        let detector = CircleCI::new(&state).await.expect("should detect CircleCI");
+
+        // The pre-issued token lacks the requested claims, so we need the CLI,
+        // which isn't available.
+        let request = TokenRequest::new("test-org-id").with_claim("env", "prod");
+        assert!(matches!(
+            detector.request(&request).await,
+            Err(super::Error::Execution(
+                crate::CommandError::NotFound { .. }
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_organization_preissued_token() {
+        let mut scope = EnvScope::new();
+        let v1 = fake_jwt(json!({"aud": "test-org-id", "v": 1}));
+        let v2 = fake_jwt(json!({"aud": "test-org-id", "v": 2}));
+
+        scope.setenv("CIRCLECI", "true");
+        scope.setenv("CIRCLE_OIDC_TOKEN", &v1);
+        scope.setenv("CIRCLE_OIDC_TOKEN_V2", &v2);
+
+        let state = crate::DetectionState {
+            circleci: Options::default().with_issuer(Issuer::Organization),
+            ..Default::default()
+        };
+        let detector = CircleCI::new(&state).await.expect("should detect CircleCI");
+
+        // The V2 token is preferred.
+        let token = detector
+            .detect("test-org-id")
+            .await
+            .expect("should use pre-issued token");
+        assert_eq!(token.reveal(), v2);
+
+        // The V1 token is used if it's the only one available.
+        scope.unsetenv("CIRCLE_OIDC_TOKEN_V2");
+        let token = detector
+            .detect("test-org-id")
+            .await
+            .expect("should use pre-issued token");
+        assert_eq!(token.reveal(), v1);
+    }
+
+    #[tokio::test]
+    async fn test_organization_preissued_token_wrong_audience() {
+        let mut scope = EnvScope::new();
+        let empty = tempfile::tempdir().unwrap();
+
+        scope.setenv("CIRCLECI", "true");
+        scope.setenv("PATH", empty.path().to_str().unwrap());
+        scope.setenv(
+            "CIRCLE_OIDC_TOKEN_V2",
+            &fake_jwt(json!({"aud": "test-org-id"})),
+        );
+        scope.unsetenv("CIRCLE_OIDC_TOKEN");
+
+        let state = crate::DetectionState {
+            circleci: Options::default().with_issuer(Issuer::Organization),
+            ..Default::default()
+        };
+        let detector = CircleCI::new(&state).await.expect("should detect CircleCI");
+
+        // The pre-issued token isn't for our audience, so we need the CLI,
+        // which isn't available.
+        assert!(matches!(
+            detector.detect("bupkis").await,
+            Err(super::Error::Execution(
+                crate::CommandError::NotFound { .. }
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_root_ignores_preissued_token() {
+        let mut scope = EnvScope::new();
+        let empty = tempfile::tempdir().unwrap();
+
+        scope.setenv("CIRCLECI", "true");
+        scope.setenv("PATH", empty.path().to_str().unwrap());
+        scope.setenv(
+            "CIRCLE_OIDC_TOKEN_V2",
+            &fake_jwt(json!({"aud": "test-org-id"})),
+        );
+
+        let state = Default::default();
+        let detector = CircleCI::new(&state).await.expect("should detect CircleCI");
+
+        // Pre-issued tokens come from the organization issuer, so they
+        // can't satisfy a request for the root issuer.
+        assert!(matches!(
+            detector.detect("test-org-id").await,
+            Err(super::Error::Execution(
+                crate::CommandError::NotFound { .. }
+            ))
+        ));
+    }
 }
@@ -0,0 +1,439 @@
+//! Helpers for CLI-based detection strategies.
+
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    process::{ExitStatus, Stdio},
+    time::Duration,
+};
+
+/// The default time limit for a CLI tool to produce a token.
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Possible errors when running a CLI tool.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The program couldn't be found.
+    #[error("`{program}` was not found ({})", searched_hint(searched))]
+    NotFound {
+        /// The program that was run.
+        program: String,
+        /// The paths at which the program was looked for.
+        searched: Vec<PathBuf>,
+    },
+    /// The program couldn't be started.
+    #[error("failed to run `{program}`")]
+    Spawn {
+        /// The program that was run.
+        program: String,
+        /// The underlying I/O error.
+        #[source]
+        source: std::io::Error,
+    },
+    /// The program didn't finish in time, and was killed.
+    #[error("`{program}` timed out after {}s", timeout.as_secs_f64())]
+    TimedOut {
+        /// The program that was run.
+        program: String,
+        /// The time limit the program exceeded.
+        timeout: Duration,
+    },
+    /// The program exited unsuccessfully.
+    #[error("`{program}` exited with {status}: '{stderr}'")]
+    NonZeroExit {
+        /// The program that was run.
+        program: String,
+        /// The program's exit status.
+        status: ExitStatus,
+        /// The program's standard error, with any secrets redacted.
+        stderr: String,
+    },
+}
+
+fn searched_hint(searched: &[PathBuf]) -> String {
+    if searched.is_empty() {
+        "PATH is empty or unset".into()
+    } else {
+        let searched = searched
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!("searched: {searched}")
+    }
+}
+
+/// Returns the explicitly configured path for a CLI tool, if any: either
+/// `configured` (e.g. from the detector's options), or the path in `env_var`.
+pub(crate) fn configured_program(configured: Option<&Path>, env_var: &str) -> Option<PathBuf> {
+    configured.map(Path::to_path_buf).or_else(|| {
+        std::env::var_os(env_var)
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+    })
+}
+
+/// Returns the paths at which `program` would be looked for when run:
+/// the program itself if it's a path, or each candidate on `PATH` otherwise.
+fn searched_paths(program: &Path) -> Vec<PathBuf> {
+    if program.components().count() > 1 {
+        return vec![program.to_path_buf()];
+    }
+
+    let Some(path) = std::env::var_os("PATH") else {
+        return vec![];
+    };
+    std::env::split_paths(&path)
+        .flat_map(|dir| candidates(&dir, &program.to_string_lossy()))
+        .collect()
+}
+
+/// A CLI tool invocation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Invocation {
+    pub(crate) program: PathBuf,
+    pub(crate) args: Vec<String>,
+}
+
+impl Invocation {
+    pub(crate) fn new(program: impl Into<PathBuf>, args: Vec<String>) -> Self {
+        Invocation {
+            program: program.into(),
+            args,
+        }
+    }
+}
+
+/// The future returned by [`CommandRunner::run`].
+pub(crate) type RunFuture<'a> = Pin<Box<dyn Future<Output = Result<Vec<u8>, Error>> + Send + 'a>>;
+
+/// Runs CLI tools on behalf of detection strategies.
+///
+/// This exists so that tests can script the tools' responses.
+pub(crate) trait CommandRunner: Send + Sync {
+    /// Runs the given invocation to completion, returning its standard output.
+    fn run(&self, invocation: Invocation, timeout: Duration) -> RunFuture<'_>;
+}
+
+/// A [`CommandRunner`] that runs real processes.
+pub(crate) struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, invocation: Invocation, timeout: Duration) -> RunFuture<'_> {
+        let mut command = tokio::process::Command::new(invocation.program);
+        command.args(invocation.args);
+        Box::pin(output(command, timeout))
+    }
+}
+
+/// Runs the given command to completion, returning its standard output.
+///
+/// The command is killed if it doesn't finish within `timeout`, or if
+/// the returned future is dropped before it finishes.
+pub(crate) async fn output(
+    mut command: tokio::process::Command,
+    timeout: Duration,
+) -> Result<Vec<u8>, Error> {
+    let program_path = PathBuf::from(command.as_std().get_program());
+    let program = program_path.to_string_lossy().into_owned();
+
+    let child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|source| match source.kind() {
+            std::io::ErrorKind::NotFound => Error::NotFound {
+                program: program.clone(),
+                searched: searched_paths(&program_path),
+            },
+            _ => Error::Spawn {
+                program: program.clone(),
+                source,
+            },
+        })?;
+
+    // On timeout, dropping the `wait_with_output` future drops (and kills) the child.
+    let output = match tokio::time::timeout(timeout, child.wait_with_output()).await {
+        Ok(output) => output.map_err(|source| Error::Spawn {
+            program: program.clone(),
+            source,
+        })?,
+        Err(_) => return Err(Error::TimedOut { program, timeout }),
+    };
+
+    if !output.status.success() {
+        return Err(Error::NonZeroExit {
+            program,
+            status: output.status,
+            stderr: crate::redact::redact(String::from_utf8_lossy(&output.stderr).trim()),
+        });
+    }
+
+    Ok(output.stdout)
+}
+
+/// Searches `PATH` for the given program, returning the first match.
+pub(crate) fn find_executable(program: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+
+    std::env::split_paths(&path)
+        .flat_map(|dir| candidates(&dir, program))
+        .find(|candidate| is_executable(candidate))
+}
+
+/// Returns the possible paths for the given program within a directory.
+fn candidates(dir: &Path, program: &str) -> Vec<PathBuf> {
+    if cfg!(windows) {
+        let extensions = std::env::var("PATHEXT").unwrap_or_else(|_| ".COM;.EXE;.BAT;.CMD".into());
+        extensions
+            .split(';')
+            .filter(|ext| !ext.is_empty())
+            .map(|ext| dir.join(format!("{program}{ext}")))
+            .chain(std::iter::once(dir.join(program)))
+            .collect()
+    } else {
+        vec![dir.join(program)]
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    path.metadata()
+        .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        collections::VecDeque,
+        path::{Path, PathBuf},
+        sync::Mutex,
+        time::Duration,
+    };
+
+    use crate::tests::EnvScope;
+
+    use super::{
+        CommandRunner, Error, Invocation, RunFuture, configured_program, find_executable, output,
+    };
+
+    /// A [`CommandRunner`] that returns scripted responses, in order,
+    /// and records each invocation.
+    pub(crate) struct ScriptedRunner {
+        responses: Mutex<VecDeque<Result<Vec<u8>, Error>>>,
+        invocations: Mutex<Vec<Invocation>>,
+    }
+
+    impl ScriptedRunner {
+        pub(crate) fn new(responses: impl IntoIterator<Item = Result<Vec<u8>, Error>>) -> Self {
+            ScriptedRunner {
+                responses: Mutex::new(responses.into_iter().collect()),
+                invocations: Mutex::new(vec![]),
+            }
+        }
+
+        /// Returns the invocations made so far.
+        pub(crate) fn invocations(&self) -> Vec<Invocation> {
+            self.invocations.lock().unwrap().clone()
+        }
+    }
+
+    impl CommandRunner for ScriptedRunner {
+        fn run(&self, invocation: Invocation, _timeout: Duration) -> RunFuture<'_> {
+            self.invocations.lock().unwrap().push(invocation);
+            let response = self
+                .responses
+                .lock()
+                .unwrap()
+                .pop_front()
+                .expect("unexpected invocation");
+            Box::pin(async move { response })
+        }
+    }
+
+    /// Returns a scripted non-zero exit for `program`.
+    #[cfg(unix)]
+    pub(crate) fn non_zero_exit(program: &str, code: i32, stderr: &str) -> Error {
+        use std::os::unix::process::ExitStatusExt as _;
+
+        Error::NonZeroExit {
+            program: program.into(),
+            status: std::process::ExitStatus::from_raw(code << 8),
+            stderr: stderr.into(),
+        }
+    }
+
+    /// Creates an (empty) executable file with the given name in `dir`.
+    pub(crate) fn touch_executable(dir: &Path, name: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, "").unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt as _;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        path
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_find_executable() {
+        let mut scope = EnvScope::new();
+        let empty = tempfile::tempdir().unwrap();
+        let bin = tempfile::tempdir().unwrap();
+        let expected = touch_executable(bin.path(), "some-tool");
+        std::fs::write(empty.path().join("not-executable"), "").unwrap();
+
+        scope.setenv(
+            "PATH",
+            std::env::join_paths([empty.path(), bin.path()])
+                .unwrap()
+                .to_str()
+                .unwrap(),
+        );
+
+        assert_eq!(find_executable("some-tool"), Some(expected));
+        assert_eq!(find_executable("not-executable"), None);
+        assert_eq!(find_executable("missing-tool"), None);
+    }
+
+    fn sh(script: &str) -> tokio::process::Command {
+        let mut command = tokio::process::Command::new("sh");
+        command.args(["-c", script]);
+        command
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn test_output() {
+        let stdout = output(sh("echo hello"), Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_eq!(stdout, b"hello\n");
+    }
+
+    #[tokio::test]
+    async fn test_output_not_found() {
+        let command = tokio::process::Command::new("ambient-id-definitely-missing");
+        assert!(matches!(
+            output(command, Duration::from_secs(10)).await,
+            Err(Error::NotFound { program, .. }) if program == "ambient-id-definitely-missing"
+        ));
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn test_output_not_found_searched() {
+        let mut scope = EnvScope::new();
+        let first = tempfile::tempdir().unwrap();
+        let second = tempfile::tempdir().unwrap();
+        scope.setenv(
+            "PATH",
+            std::env::join_paths([first.path(), second.path()])
+                .unwrap()
+                .to_str()
+                .unwrap(),
+        );
+
+        let command = tokio::process::Command::new("some-tool");
+        let err = output(command, Duration::from_secs(10)).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "`some-tool` was not found (searched: {}, {})",
+                first.path().join("some-tool").display(),
+                second.path().join("some-tool").display(),
+            )
+        );
+
+        // Explicit paths aren't looked up on `PATH`.
+        let explicit = first.path().join("some-tool");
+        let command = tokio::process::Command::new(&explicit);
+        assert!(matches!(
+            output(command, Duration::from_secs(10)).await,
+            Err(Error::NotFound { searched, .. }) if searched == [explicit]
+        ));
+    }
+
+    #[test]
+    fn test_configured_program() {
+        let mut scope = EnvScope::new();
+        scope.unsetenv("AMBIENT_ID_SOME_TOOL");
+        assert_eq!(configured_program(None, "AMBIENT_ID_SOME_TOOL"), None);
+
+        scope.setenv("AMBIENT_ID_SOME_TOOL", "");
+        assert_eq!(configured_program(None, "AMBIENT_ID_SOME_TOOL"), None);
+
+        scope.setenv("AMBIENT_ID_SOME_TOOL", "/opt/tool");
+        assert_eq!(
+            configured_program(None, "AMBIENT_ID_SOME_TOOL"),
+            Some("/opt/tool".into())
+        );
+
+        // Explicit configuration takes precedence over the environment.
+        assert_eq!(
+            configured_program(Some(Path::new("/usr/bin/tool")), "AMBIENT_ID_SOME_TOOL"),
+            Some("/usr/bin/tool".into())
+        );
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn test_output_non_zero_exit() {
+        let err = output(sh("echo oops >&2; exit 3"), Duration::from_secs(10))
+            .await
+            .unwrap_err();
+
+        let Error::NonZeroExit {
+            program,
+            status,
+            stderr,
+        } = &err
+        else {
+            panic!("expected non-zero exit, got {err:?}");
+        };
+        assert_eq!(program, "sh");
+        assert_eq!(status.code(), Some(3));
+        assert_eq!(stderr, "oops");
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn test_output_non_zero_exit_redacted() {
+        let err = output(
+            sh("echo 'bad token: eyJhbGciOiJSUzI1NiJ9.eyJhdWQiOiJ4In0.c2ln' >&2; exit 1"),
+            Duration::from_secs(10),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(
+            err,
+            Error::NonZeroExit { stderr, .. } if stderr == "bad token: [REDACTED]"
+        ));
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn test_output_timed_out() {
+        let start = std::time::Instant::now();
+        let err = output(sh("sleep 30"), Duration::from_millis(100))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Error::TimedOut { .. }));
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+}
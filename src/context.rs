@@ -0,0 +1,197 @@
+//! Build context for the detected CI provider.
+
+use crate::Provider;
+
+/// The CI build that the current process is running in.
+///
+/// This is read from each provider's documented environment variables,
+/// without requesting a token or making any network calls. Fields are
+/// `None` when the provider doesn't set the corresponding variable.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+#[non_exhaustive]
+pub struct BuildContext {
+    /// The detected provider.
+    pub provider: Provider,
+    /// The repository being built, as the provider identifies it: a slug
+    /// like `owner/repo` on GitHub Actions and GitLab CI, or a clone URL on
+    /// Buildkite and CircleCI.
+    pub repository: Option<String>,
+    /// The Git ref being built, e.g. `refs/heads/main` on GitHub Actions
+    /// or a branch or tag name elsewhere.
+    #[serde(rename = "ref")]
+    pub git_ref: Option<String>,
+    /// The full SHA of the commit being built.
+    pub commit_sha: Option<String>,
+    /// The ID of the run (GitHub Actions workflow run, GitLab CI pipeline,
+    /// Buildkite build or CircleCI workflow).
+    pub run_id: Option<String>,
+    /// A URL for the current job, or for its run if the provider doesn't
+    /// expose one for individual jobs.
+    pub job_url: Option<String>,
+}
+
+impl BuildContext {
+    /// Returns a context for `provider` with no details.
+    pub(crate) fn new(provider: Provider) -> Self {
+        BuildContext {
+            provider,
+            repository: None,
+            git_ref: None,
+            commit_sha: None,
+            run_id: None,
+            job_url: None,
+        }
+    }
+}
+
+/// Returns the value of an environment variable, treating empty values
+/// as unset.
+pub(crate) fn var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// Returns the build context of the first CI provider detected, in the
+/// same order as token detection.
+pub(crate) fn detect() -> Option<BuildContext> {
+    crate::github::build_context()
+        .or_else(crate::gitlab::build_context)
+        .or_else(crate::buildkite::build_context)
+        .or_else(crate::circleci::build_context)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Provider, tests::EnvScope};
+
+    use super::detect;
+
+    /// Unsets every provider's marker variable.
+    fn no_provider_env(scope: &mut EnvScope) {
+        for var in ["GITHUB_ACTIONS", "GITLAB_CI", "BUILDKITE", "CIRCLECI"] {
+            scope.unsetenv(var);
+        }
+    }
+
+    #[test]
+    fn test_no_provider() {
+        let mut scope = EnvScope::new();
+        no_provider_env(&mut scope);
+
+        assert_eq!(detect(), None);
+    }
+
+    #[test]
+    fn test_github_actions() {
+        let mut scope = EnvScope::new();
+        no_provider_env(&mut scope);
+        scope.setenv("GITHUB_ACTIONS", "true");
+        scope.setenv("GITHUB_SERVER_URL", "https://github.com");
+        scope.setenv("GITHUB_REPOSITORY", "octo-org/octo-repo");
+        scope.setenv("GITHUB_REF", "refs/heads/main");
+        scope.setenv("GITHUB_SHA", "ffac537e6cbbf934b08745a378932722df287a53");
+        scope.setenv("GITHUB_RUN_ID", "1658821493");
+        scope.setenv("GITHUB_RUN_ATTEMPT", "2");
+
+        let context = detect().expect("should detect GitHub Actions");
+        assert_eq!(
+            serde_json::to_value(&context).unwrap(),
+            serde_json::json!({
+                "provider": "github-actions",
+                "repository": "octo-org/octo-repo",
+                "ref": "refs/heads/main",
+                "commit_sha": "ffac537e6cbbf934b08745a378932722df287a53",
+                "run_id": "1658821493",
+                "job_url": "https://github.com/octo-org/octo-repo/actions/runs/1658821493/attempts/2",
+            })
+        );
+    }
+
+    #[test]
+    fn test_gitlab_ci() {
+        let mut scope = EnvScope::new();
+        no_provider_env(&mut scope);
+        scope.setenv("GITLAB_CI", "true");
+        scope.setenv("CI_PROJECT_PATH", "gitlab-org/gitlab");
+        scope.setenv("CI_COMMIT_REF_NAME", "main");
+        scope.setenv("CI_COMMIT_SHA", "1ecfd275763eff1d6b4844ea3168962458c9f27a");
+        scope.setenv("CI_PIPELINE_ID", "1000");
+        scope.setenv(
+            "CI_JOB_URL",
+            "https://gitlab.com/gitlab-org/gitlab/-/jobs/2000",
+        );
+
+        let context = detect().expect("should detect GitLab CI");
+        assert_eq!(context.provider, Provider::GitLabCI);
+        assert_eq!(context.repository.as_deref(), Some("gitlab-org/gitlab"));
+        assert_eq!(context.git_ref.as_deref(), Some("main"));
+        assert_eq!(context.run_id.as_deref(), Some("1000"));
+        assert_eq!(
+            context.job_url.as_deref(),
+            Some("https://gitlab.com/gitlab-org/gitlab/-/jobs/2000")
+        );
+    }
+
+    #[test]
+    fn test_buildkite() {
+        let mut scope = EnvScope::new();
+        no_provider_env(&mut scope);
+        scope.setenv("BUILDKITE", "true");
+        scope.setenv("BUILDKITE_REPO", "git@github.com:acme-inc/my-project.git");
+        scope.unsetenv("BUILDKITE_TAG");
+        scope.setenv("BUILDKITE_BRANCH", "main");
+        scope.setenv(
+            "BUILDKITE_COMMIT",
+            "83a20ec058e2fb00e7fa4558c4c6e81e2dcf253d",
+        );
+        scope.setenv("BUILDKITE_BUILD_ID", "f62a1b4d-10f9-4790-bc1c-e2c3a0c80983");
+        scope.setenv(
+            "BUILDKITE_BUILD_URL",
+            "https://buildkite.com/acme-inc/my-project/builds/1514",
+        );
+        scope.setenv("BUILDKITE_JOB_ID", "e44f9784-e20e-4b93-a21d-f41fd5869db9");
+
+        let context = detect().expect("should detect Buildkite");
+        assert_eq!(context.provider, Provider::Buildkite);
+        assert_eq!(context.git_ref.as_deref(), Some("main"));
+        assert_eq!(
+            context.job_url.as_deref(),
+            Some(
+                "https://buildkite.com/acme-inc/my-project/builds/1514#e44f9784-e20e-4b93-a21d-f41fd5869db9"
+            )
+        );
+
+        // Tags take precedence over branches.
+        scope.setenv("BUILDKITE_TAG", "v1.0.0");
+        assert_eq!(detect().unwrap().git_ref.as_deref(), Some("v1.0.0"));
+    }
+
+    #[test]
+    fn test_circleci() {
+        let mut scope = EnvScope::new();
+        no_provider_env(&mut scope);
+        scope.setenv("CIRCLECI", "true");
+        scope.setenv(
+            "CIRCLE_REPOSITORY_URL",
+            "git@github.com:acme-inc/my-project.git",
+        );
+        scope.unsetenv("CIRCLE_TAG");
+        scope.setenv("CIRCLE_BRANCH", "main");
+        scope.setenv("CIRCLE_SHA1", "83a20ec058e2fb00e7fa4558c4c6e81e2dcf253d");
+        scope.setenv("CIRCLE_WORKFLOW_ID", "6fa7a8b2-0a4c-4b4e-8a2c-2e0f1f2e8d11");
+        scope.setenv(
+            "CIRCLE_BUILD_URL",
+            "https://circleci.com/gh/acme-inc/my-project/123",
+        );
+
+        let context = detect().expect("should detect CircleCI");
+        assert_eq!(context.provider, Provider::CircleCI);
+        assert_eq!(
+            context.run_id.as_deref(),
+            Some("6fa7a8b2-0a4c-4b4e-8a2c-2e0f1f2e8d11")
+        );
+        assert_eq!(
+            context.job_url.as_deref(),
+            Some("https://circleci.com/gh/acme-inc/my-project/123")
+        );
+    }
+}
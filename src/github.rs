@@ -1,8 +1,18 @@
 //! GitHub Actions OIDC token detection.
 
+use std::{
+    hash::{BuildHasher as _, Hasher as _},
+    time::{Duration, SystemTime},
+};
+
+use reqwest::{StatusCode, header::RETRY_AFTER};
 use reqwest_middleware::ClientWithMiddleware;
 
-use crate::{DetectionState, DetectionStrategy};
+use crate::{
+    DetectionState, DetectionStrategy, Provider,
+    context::{BuildContext, var},
+    http::{ErrorFormat, ResponseExt as _},
+};
 
 /// Possible errors during GitHub Actions OIDC token detection.
 #[derive(Debug, thiserror::Error)]
@@ -10,12 +20,277 @@ pub enum Error {
     /// The GitHub Actions environment lacks necessary permissions.
     ///
     /// This is typically resolved by adding `id-token: write` to the
-    /// job's `permissions` block.
-    #[error("insufficient permissions: {0}")]
-    InsufficientPermissions(&'static str),
+    /// job's `permissions` block, but see `reason` for why it's missing.
+    #[error("insufficient permissions: missing {missing} ({reason}); {remediation}")]
+    InsufficientPermissions {
+        /// The missing environment variable.
+        missing: &'static str,
+        /// The likely reason the permission is missing.
+        reason: PermissionReason,
+        /// How to fix (or work around) the missing permission.
+        remediation: String,
+    },
+    /// The workflow is running under a local emulator of GitHub Actions
+    /// (e.g. `act`), which doesn't provide an ID token endpoint.
+    #[error("running under {emulator}, which emulates GitHub Actions but can't issue OIDC tokens")]
+    Emulator {
+        /// The name of the emulator.
+        emulator: &'static str,
+    },
     /// The HTTP request to fetch the ID token failed.
     #[error("HTTP request failed: {0}")]
-    Request(#[from] reqwest_middleware::Error),
+    Request(#[from] crate::HttpError),
+}
+
+/// Environment variables that are set to `true` by emulators of GitHub
+/// Actions, and the emulators' names.
+///
+/// Forgejo's runner also sets `GITEA_ACTIONS`, so it's checked first.
+const EMULATOR_MARKERS: &[(&str, &str)] = &[
+    ("ACT", "`act`"),
+    ("FORGEJO_ACTIONS", "Forgejo Actions"),
+    ("GITEA_ACTIONS", "Gitea Actions"),
+];
+
+/// Returns the name of the GitHub Actions emulator we're running under, if any.
+fn emulator() -> Option<&'static str> {
+    EMULATOR_MARKERS
+        .iter()
+        .find(|(var, _)| std::env::var(var).as_deref() == Ok("true"))
+        .map(|(_, name)| *name)
+}
+
+/// Responses with these statuses are retried.
+const RETRYABLE_STATUSES: &[StatusCode] = &[
+    StatusCode::REQUEST_TIMEOUT,
+    StatusCode::TOO_MANY_REQUESTS,
+    StatusCode::INTERNAL_SERVER_ERROR,
+    StatusCode::BAD_GATEWAY,
+    StatusCode::SERVICE_UNAVAILABLE,
+    StatusCode::GATEWAY_TIMEOUT,
+];
+
+/// The upper bound on the delay between any two attempts, before jitter.
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Options for GitHub Actions OIDC token requests.
+///
+/// GitHub's token endpoint occasionally fails transiently, so failed requests
+/// are retried with exponential backoff and jitter. A `Retry-After` header
+/// (in seconds) takes precedence over the backoff.
+#[derive(Clone, Debug)]
+pub struct Options {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_total_delay: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_total_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Options {
+    /// Sets the maximum number of attempts, including the first.
+    ///
+    /// The default is 3; a value of 1 disables retries.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Sets the delay before the first retry, which doubles for each
+    /// subsequent retry. The default is 500 milliseconds.
+    pub fn with_initial_backoff(mut self, initial_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    /// Sets the maximum total time to spend waiting between attempts.
+    ///
+    /// A retry that would exceed this is not made. The default is 10 seconds.
+    pub fn with_max_total_delay(mut self, max_total_delay: Duration) -> Self {
+        self.max_total_delay = max_total_delay;
+        self
+    }
+
+    /// Returns the delay before the given retry (starting at 1), with jitter.
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry - 1))
+            .min(MAX_BACKOFF);
+
+        // "Equal jitter": somewhere between half and all of the backoff.
+        backoff / 2 + backoff.mul_f64(jitter() / 2.0)
+    }
+}
+
+/// Returns a random number in `[0, 1]`.
+fn jitter() -> f64 {
+    // We don't need a good source of randomness, just one that differs
+    // between concurrent clients; `RandomState` is randomly seeded.
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    hasher.finish() as f64 / u64::MAX as f64
+}
+
+/// Returns the delay requested by a response's `Retry-After` header, if any.
+///
+/// Only the delay-seconds form is supported; HTTP dates are ignored.
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// Returns whether a failure to send a request may be transient.
+///
+/// Only connection failures and timeouts are; error statuses are handled
+/// separately, once a response has been received.
+fn is_retryable_error(err: &reqwest_middleware::Error) -> bool {
+    match err {
+        reqwest_middleware::Error::Reqwest(err) => err.is_connect() || err.is_timeout(),
+        reqwest_middleware::Error::Middleware(_) => false,
+    }
+}
+
+/// Why a GitHub Actions job can't obtain an ID token.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PermissionReason {
+    /// The workflow was triggered by a pull request from a fork.
+    ///
+    /// GitHub never grants `id-token: write` to such workflows.
+    ForkPullRequest,
+    /// The workflow was triggered by Dependabot.
+    ///
+    /// GitHub treats these like workflows triggered from forks.
+    Dependabot,
+    /// The job doesn't have the `id-token: write` permission.
+    MissingPermission,
+}
+
+impl std::fmt::Display for PermissionReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PermissionReason::ForkPullRequest => {
+                "the workflow was triggered by a pull request from a fork"
+            }
+            PermissionReason::Dependabot => "the workflow was triggered by Dependabot",
+            PermissionReason::MissingPermission => {
+                "the job doesn't have the `id-token: write` permission"
+            }
+        })
+    }
+}
+
+/// Pull request events whose workflows run in the context of the
+/// pull request's head, and so don't get privileges from forks.
+///
+/// Notably, this excludes `pull_request_target`.
+const PULL_REQUEST_EVENTS: &[&str] = &[
+    "pull_request",
+    "pull_request_review",
+    "pull_request_review_comment",
+];
+
+/// The parts of a pull request event payload we need to detect forks.
+#[derive(serde::Deserialize)]
+struct PullRequestEvent {
+    pull_request: PullRequest,
+}
+
+#[derive(serde::Deserialize)]
+struct PullRequest {
+    head: PullRequestBranch,
+    base: PullRequestBranch,
+}
+
+#[derive(serde::Deserialize)]
+struct PullRequestBranch {
+    repo: Option<Repository>,
+}
+
+#[derive(serde::Deserialize)]
+struct Repository {
+    full_name: String,
+}
+
+impl PermissionReason {
+    /// Works out why the current job can't obtain an ID token, from its
+    /// `GITHUB_*` environment variables.
+    fn diagnose() -> Self {
+        if std::env::var("GITHUB_ACTOR").as_deref() == Ok("dependabot[bot]") {
+            return PermissionReason::Dependabot;
+        }
+
+        let is_pull_request = std::env::var("GITHUB_EVENT_NAME")
+            .is_ok_and(|event| PULL_REQUEST_EVENTS.contains(&event.as_str()));
+        if is_pull_request && Self::event_is_from_fork() {
+            return PermissionReason::ForkPullRequest;
+        }
+
+        PermissionReason::MissingPermission
+    }
+
+    /// Returns whether the pull request in `GITHUB_EVENT_PATH` is from a fork,
+    /// i.e. its head and base repositories differ.
+    fn event_is_from_fork() -> bool {
+        std::env::var_os("GITHUB_EVENT_PATH")
+            .and_then(|path| std::fs::read(path).ok())
+            .and_then(|event| serde_json::from_slice::<PullRequestEvent>(&event).ok())
+            .is_some_and(|event| {
+                // A deleted head repository can only have been a fork.
+                let head = event.pull_request.head.repo.map(|repo| repo.full_name);
+                let base = event.pull_request.base.repo.map(|repo| repo.full_name);
+                head.is_none() || head != base
+            })
+    }
+
+    /// Returns how to fix (or work around) this reason.
+    fn remediation(self) -> String {
+        match self {
+            PermissionReason::ForkPullRequest => {
+                "GitHub doesn't issue OIDC tokens to pull requests from forks; \
+                 skip this step for them, e.g. with \
+                 `if: github.event.pull_request.head.repo.full_name == github.repository`"
+                    .into()
+            }
+            PermissionReason::Dependabot => "GitHub doesn't issue OIDC tokens to Dependabot runs; \
+                 skip this step for them, e.g. with `if: github.actor != 'dependabot[bot]'`"
+                .into(),
+            PermissionReason::MissingPermission => {
+                // e.g. `owner/repo/.github/workflows/release.yml@refs/heads/main`
+                let workflow = std::env::var("GITHUB_WORKFLOW_REF")
+                    .ok()
+                    .and_then(|workflow_ref| {
+                        let (path, _) = workflow_ref.split_once('@')?;
+                        let (_, path) = path.split_once("/.github/")?;
+                        Some(format!("`.github/{path}`"))
+                    })
+                    .unwrap_or_else(|| "the workflow".into());
+
+                format!("add `permissions: id-token: write` to the job in {workflow}")
+            }
+        }
+    }
 }
 
 /// The JSON payload returned by GitHub's ID token endpoint.
@@ -24,22 +299,60 @@ struct TokenRequestResponse {
     value: String,
 }
 
+/// Returns whether we're running in GitHub Actions.
+fn detected() -> bool {
+    // Per GitHub docs, this is exactly "true" when
+    // running in GitHub Actions.
+    std::env::var("GITHUB_ACTIONS").is_ok_and(|v| v == "true")
+}
+
+/// Returns the build context of the current GitHub Actions workflow run.
+///
+/// See: <https://docs.github.com/en/actions/reference/workflows-and-actions/variables>
+pub(crate) fn build_context() -> Option<BuildContext> {
+    if !detected() {
+        return None;
+    }
+
+    let repository = var("GITHUB_REPOSITORY");
+    let run_id = var("GITHUB_RUN_ID");
+    // GitHub doesn't expose the job's ID, so this is the run attempt's URL.
+    let job_url = match (var("GITHUB_SERVER_URL"), &repository, &run_id) {
+        (Some(server), Some(repository), Some(run_id)) => {
+            let mut url = format!("{server}/{repository}/actions/runs/{run_id}");
+            if let Some(attempt) = var("GITHUB_RUN_ATTEMPT") {
+                url.push_str(&format!("/attempts/{attempt}"));
+            }
+            Some(url)
+        }
+        _ => None,
+    };
+
+    Some(BuildContext {
+        repository,
+        git_ref: var("GITHUB_REF"),
+        commit_sha: var("GITHUB_SHA"),
+        run_id,
+        job_url,
+        ..BuildContext::new(Provider::GitHubActions)
+    })
+}
+
 pub(crate) struct GitHubActions {
     client: ClientWithMiddleware,
+    options: Options,
 }
 
 impl DetectionStrategy for GitHubActions {
     type Error = Error;
 
-    fn new(state: &DetectionState) -> Option<Self> {
-        std::env::var("GITHUB_ACTIONS")
-            .ok()
-            // Per GitHub docs, this is exactly "true" when
-            // running in GitHub Actions.
-            .filter(|v| v == "true")
-            .map(|_| GitHubActions {
-                client: state.client.clone(),
-            })
+    const PROVIDER: Provider = Provider::GitHubActions;
+
+    async fn new(state: &DetectionState) -> Option<Self> {
+        detected().then(|| GitHubActions {
+            client: state.client.clone(),
+            options: state.github.clone(),
+        })
     }
 
     /// On GitHub Actions, the OIDC token URL is provided
@@ -48,25 +361,63 @@ impl DetectionStrategy for GitHubActions {
     /// environment variable to authenticate the request.
     ///
     /// The absence of either variable indicates insufficient permissions.
+    /// In that case, we inspect the workflow run's context to explain why,
+    /// unless we're running under an emulator like `act` that never
+    /// provides them.
+    ///
+    /// Transient failures of the request are retried according to [`Options`].
     async fn detect(&self, audience: &str) -> Result<crate::IdToken, Self::Error> {
+        let insufficient_permissions = |missing| {
+            if let Some(emulator) = emulator() {
+                return Error::Emulator { emulator };
+            }
+
+            let reason = PermissionReason::diagnose();
+            Error::InsufficientPermissions {
+                missing,
+                reason,
+                remediation: reason.remediation(),
+            }
+        };
+
         let url = std::env::var("ACTIONS_ID_TOKEN_REQUEST_URL")
-            .map_err(|_| Error::InsufficientPermissions("missing ACTIONS_ID_TOKEN_REQUEST_URL"))?;
-        let token = std::env::var("ACTIONS_ID_TOKEN_REQUEST_TOKEN").map_err(|_| {
-            Error::InsufficientPermissions("missing ACTIONS_ID_TOKEN_REQUEST_TOKEN")
-        })?;
-
-        let resp = self
-            .client
-            .get(&url)
-            .bearer_auth(token)
-            .query(&[("audience", audience)])
-            .send()
+            .map_err(|_| insufficient_permissions("ACTIONS_ID_TOKEN_REQUEST_URL"))?;
+        let token = std::env::var("ACTIONS_ID_TOKEN_REQUEST_TOKEN")
+            .map_err(|_| insufficient_permissions("ACTIONS_ID_TOKEN_REQUEST_TOKEN"))?;
+
+        let mut retry = 0;
+        let mut total_delay = Duration::ZERO;
+        let resp = loop {
+            let result = self
+                .client
+                .get(&url)
+                .bearer_auth(&token)
+                .query(&[("audience", audience)])
+                .send()
+                .await;
+
+            retry += 1;
+            let delay = match &result {
+                Ok(resp) if RETRYABLE_STATUSES.contains(&resp.status()) => {
+                    retry_after(resp).unwrap_or_else(|| self.options.backoff(retry))
+                }
+                Err(err) if is_retryable_error(err) => self.options.backoff(retry),
+                _ => break result.map_err(crate::HttpError::from)?,
+            };
+
+            total_delay += delay;
+            if retry >= self.options.max_attempts || total_delay > self.options.max_total_delay {
+                break result.map_err(crate::HttpError::from)?;
+            }
+            tokio::time::sleep(delay).await;
+        };
+
+        let resp = resp
+            .check(ErrorFormat::Message)
             .await?
-            .error_for_status()
-            .map_err(reqwest_middleware::Error::Reqwest)?
             .json::<TokenRequestResponse>()
             .await
-            .map_err(reqwest_middleware::Error::Reqwest)?;
+            .map_err(crate::HttpError::from)?;
 
         Ok(crate::IdToken(resp.value.into()))
     }
@@ -81,7 +432,68 @@ mod tests {
 
     use crate::{DetectionStrategy as _, tests::EnvScope};
 
-    use super::GitHubActions;
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    };
+
+    use tokio::io::AsyncWriteExt as _;
+
+    use super::{GitHubActions, Options, PermissionReason};
+
+    /// Sets up a GitHub Actions environment without `id-token: write`,
+    /// triggered by the given event (written to `dir`).
+    fn no_permission_env(
+        scope: &mut EnvScope,
+        dir: &std::path::Path,
+        event_name: &str,
+        event: serde_json::Value,
+    ) {
+        let event_path = dir.join("event.json");
+        std::fs::write(&event_path, event.to_string()).unwrap();
+
+        scope.setenv("GITHUB_ACTIONS", "true");
+        scope.unsetenv("ACTIONS_ID_TOKEN_REQUEST_URL");
+        scope.unsetenv("ACTIONS_ID_TOKEN_REQUEST_TOKEN");
+        for (var, _) in super::EMULATOR_MARKERS {
+            scope.unsetenv(var);
+        }
+        scope.setenv("GITHUB_ACTOR", "octocat");
+        scope.setenv("GITHUB_EVENT_NAME", event_name);
+        scope.setenv("GITHUB_EVENT_PATH", event_path.to_str().unwrap());
+        scope.setenv(
+            "GITHUB_WORKFLOW_REF",
+            "octo-org/octo-repo/.github/workflows/release.yml@refs/heads/main",
+        );
+    }
+
+    fn pull_request_event(head: &str, base: &str) -> serde_json::Value {
+        serde_json::json!({
+            "pull_request": {
+                "head": { "repo": { "full_name": head } },
+                "base": { "repo": { "full_name": base } },
+            }
+        })
+    }
+
+    async fn insufficient_permissions() -> (&'static str, PermissionReason, String) {
+        let state = Default::default();
+        let detector = GitHubActions::new(&state)
+            .await
+            .expect("should detect GitHub Actions");
+
+        match detector.detect("bupkis").await {
+            Err(super::Error::InsufficientPermissions {
+                missing,
+                reason,
+                remediation,
+            }) => (missing, reason, remediation),
+            _ => panic!("expected insufficient permissions error"),
+        }
+    }
 
     /// Happy path for GitHub Actions OIDC token detection.
     #[tokio::test]
@@ -89,7 +501,9 @@ mod tests {
     async fn test_1p_detection_ok() {
         let _ = EnvScope::new();
         let state = Default::default();
-        let detector = GitHubActions::new(&state).expect("should detect GitHub Actions");
+        let detector = GitHubActions::new(&state)
+            .await
+            .expect("should detect GitHub Actions");
         let token = detector
             .detect("test_1p_detection_ok")
             .await
@@ -107,11 +521,13 @@ mod tests {
         scope.unsetenv("ACTIONS_ID_TOKEN_REQUEST_URL");
 
         let state = Default::default();
-        let detector = GitHubActions::new(&state).expect("should detect GitHub Actions");
+        let detector = GitHubActions::new(&state)
+            .await
+            .expect("should detect GitHub Actions");
 
         match detector.detect("test_1p_detection_missing_url").await {
-            Err(super::Error::InsufficientPermissions(what)) => {
-                assert_eq!(what, "missing ACTIONS_ID_TOKEN_REQUEST_URL")
+            Err(super::Error::InsufficientPermissions { missing, .. }) => {
+                assert_eq!(missing, "ACTIONS_ID_TOKEN_REQUEST_URL")
             }
             _ => panic!("expected insufficient permissions error"),
         }
@@ -126,23 +542,134 @@ mod tests {
         scope.unsetenv("ACTIONS_ID_TOKEN_REQUEST_TOKEN");
 
         let state = Default::default();
-        let detector = GitHubActions::new(&state).expect("should detect GitHub Actions");
+        let detector = GitHubActions::new(&state)
+            .await
+            .expect("should detect GitHub Actions");
 
         match detector.detect("test_1p_detection_missing_token").await {
-            Err(super::Error::InsufficientPermissions(what)) => {
-                assert_eq!(what, "missing ACTIONS_ID_TOKEN_REQUEST_TOKEN")
+            Err(super::Error::InsufficientPermissions { missing, .. }) => {
+                assert_eq!(missing, "ACTIONS_ID_TOKEN_REQUEST_TOKEN")
             }
             _ => panic!("expected insufficient permissions error"),
         }
     }
 
+    #[tokio::test]
+    async fn test_missing_permission() {
+        let mut scope = EnvScope::new();
+        let dir = tempfile::tempdir().unwrap();
+        no_permission_env(&mut scope, dir.path(), "push", serde_json::json!({}));
+
+        let (missing, reason, remediation) = insufficient_permissions().await;
+        assert_eq!(missing, "ACTIONS_ID_TOKEN_REQUEST_URL");
+        assert_eq!(reason, PermissionReason::MissingPermission);
+        assert_eq!(
+            remediation,
+            "add `permissions: id-token: write` to the job in `.github/workflows/release.yml`"
+        );
+
+        // Same-repository pull requests can have the permission.
+        no_permission_env(
+            &mut scope,
+            dir.path(),
+            "pull_request",
+            pull_request_event("octo-org/octo-repo", "octo-org/octo-repo"),
+        );
+        let (_, reason, _) = insufficient_permissions().await;
+        assert_eq!(reason, PermissionReason::MissingPermission);
+
+        // Only the token is missing.
+        scope.setenv("ACTIONS_ID_TOKEN_REQUEST_URL", "https://example.com");
+        let (missing, _, _) = insufficient_permissions().await;
+        assert_eq!(missing, "ACTIONS_ID_TOKEN_REQUEST_TOKEN");
+    }
+
+    #[tokio::test]
+    async fn test_fork_pull_request() {
+        let mut scope = EnvScope::new();
+        let dir = tempfile::tempdir().unwrap();
+        no_permission_env(
+            &mut scope,
+            dir.path(),
+            "pull_request",
+            pull_request_event("someone/octo-repo", "octo-org/octo-repo"),
+        );
+
+        let (_, reason, _) = insufficient_permissions().await;
+        assert_eq!(reason, PermissionReason::ForkPullRequest);
+
+        // `pull_request_target` runs in the base repository's context.
+        scope.setenv("GITHUB_EVENT_NAME", "pull_request_target");
+        let (_, reason, _) = insufficient_permissions().await;
+        assert_eq!(reason, PermissionReason::MissingPermission);
+    }
+
+    #[tokio::test]
+    async fn test_dependabot() {
+        let mut scope = EnvScope::new();
+        let dir = tempfile::tempdir().unwrap();
+        no_permission_env(
+            &mut scope,
+            dir.path(),
+            "pull_request",
+            pull_request_event("octo-org/octo-repo", "octo-org/octo-repo"),
+        );
+        scope.setenv("GITHUB_ACTOR", "dependabot[bot]");
+
+        let (_, reason, remediation) = insufficient_permissions().await;
+        assert_eq!(reason, PermissionReason::Dependabot);
+        assert!(remediation.contains("dependabot[bot]"));
+    }
+
+    #[tokio::test]
+    async fn test_emulator() {
+        let mut scope = EnvScope::new();
+        let dir = tempfile::tempdir().unwrap();
+        no_permission_env(&mut scope, dir.path(), "push", serde_json::json!({}));
+
+        let state = Default::default();
+        let detector = GitHubActions::new(&state)
+            .await
+            .expect("should detect GitHub Actions");
+
+        scope.setenv("ACT", "true");
+        assert!(matches!(
+            detector.detect("bupkis").await,
+            Err(super::Error::Emulator { emulator: "`act`" })
+        ));
+
+        scope.unsetenv("ACT");
+        scope.setenv("GITEA_ACTIONS", "true");
+        scope.setenv("FORGEJO_ACTIONS", "true");
+        assert!(matches!(
+            detector.detect("bupkis").await,
+            Err(super::Error::Emulator {
+                emulator: "Forgejo Actions"
+            })
+        ));
+
+        // Emulators that do provide an ID token endpoint are used as usual.
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                wiremock::ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({ "value": "emulated-token" })),
+            )
+            .mount(&server)
+            .await;
+        token_endpoint_env(&mut scope, &server);
+
+        let token = detector.detect("bupkis").await.expect("should fetch token");
+        assert_eq!(token.reveal(), "emulated-token");
+    }
+
     #[tokio::test]
     async fn test_not_detected() {
         let mut scope = EnvScope::new();
         scope.unsetenv("GITHUB_ACTIONS");
 
         let state = Default::default();
-        assert!(GitHubActions::new(&state).is_none());
+        assert!(GitHubActions::new(&state).await.is_none());
     }
 
     #[tokio::test]
@@ -151,7 +678,7 @@ mod tests {
         scope.setenv("GITHUB_ACTIONS", "true");
 
         let state = Default::default();
-        assert!(GitHubActions::new(&state).is_some());
+        assert!(GitHubActions::new(&state).await.is_some());
     }
 
     #[tokio::test]
@@ -161,7 +688,7 @@ mod tests {
             scope.setenv("GITHUB_ACTIONS", value);
 
             let state = Default::default();
-            assert!(GitHubActions::new(&state).is_none());
+            assert!(GitHubActions::new(&state).await.is_none());
         }
     }
 
@@ -180,12 +707,232 @@ mod tests {
             .mount(&server)
             .await;
 
-        let state = Default::default();
-        let detector = GitHubActions::new(&state).expect("should detect GitHub Actions");
+        let detector = fast_retry_detector(Options::default()).await;
         assert!(matches!(
             detector.detect("test_error_code").await,
             Err(super::Error::Request(_))
         ));
+
+        // The request was retried up to the default number of attempts.
+        assert_eq!(server.received_requests().await.unwrap().len(), 3);
+    }
+
+    /// Returns a detector whose retries don't (noticeably) wait.
+    async fn fast_retry_detector(options: Options) -> GitHubActions {
+        let state = crate::DetectionState {
+            github: options.with_initial_backoff(Duration::from_millis(1)),
+            ..Default::default()
+        };
+        GitHubActions::new(&state)
+            .await
+            .expect("should detect GitHub Actions")
+    }
+
+    /// Sets up a GitHub Actions environment whose token endpoint is `server`.
+    fn token_endpoint_env(scope: &mut EnvScope, server: &MockServer) {
+        scope.setenv("GITHUB_ACTIONS", "true");
+        scope.setenv("ACTIONS_ID_TOKEN_REQUEST_TOKEN", "bogus");
+        scope.setenv("ACTIONS_ID_TOKEN_REQUEST_URL", &server.uri());
+    }
+
+    #[tokio::test]
+    async fn test_retry_then_ok() {
+        let mut scope = EnvScope::new();
+        let server = MockServer::start().await;
+        token_endpoint_env(&mut scope, &server);
+
+        Mock::given(method("GET"))
+            .respond_with(wiremock::ResponseTemplate::new(502))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(wiremock::ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(
+                wiremock::ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({ "value": "test-retry-token" })),
+            )
+            .mount(&server)
+            .await;
+
+        let detector = fast_retry_detector(Options::default()).await;
+        let token = detector
+            .detect("test_retry_then_ok")
+            .await
+            .expect("should fetch token after retrying");
+        assert_eq!(token.reveal(), "test-retry-token");
+        assert_eq!(server.received_requests().await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_retry_max_attempts() {
+        let mut scope = EnvScope::new();
+        let server = MockServer::start().await;
+        token_endpoint_env(&mut scope, &server);
+
+        Mock::given(method("GET"))
+            .respond_with(wiremock::ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+
+        let detector = fast_retry_detector(Options::default().with_max_attempts(5)).await;
+        assert!(detector.detect("test_retry_max_attempts").await.is_err());
+        assert_eq!(server.received_requests().await.unwrap().len(), 5);
+
+        let detector = fast_retry_detector(Options::default().with_max_attempts(1)).await;
+        assert!(detector.detect("test_retry_max_attempts").await.is_err());
+        assert_eq!(server.received_requests().await.unwrap().len(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_no_retry_client_error() {
+        let mut scope = EnvScope::new();
+        let server = MockServer::start().await;
+        token_endpoint_env(&mut scope, &server);
+
+        Mock::given(method("GET"))
+            .respond_with(wiremock::ResponseTemplate::new(403))
+            .mount(&server)
+            .await;
+
+        let detector = fast_retry_detector(Options::default()).await;
+        assert!(detector.detect("test_no_retry_client_error").await.is_err());
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_retry_after() {
+        let mut scope = EnvScope::new();
+        let server = MockServer::start().await;
+        token_endpoint_env(&mut scope, &server);
+
+        Mock::given(method("GET"))
+            .respond_with(wiremock::ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(
+                wiremock::ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({ "value": "test-retry-after-token" })),
+            )
+            .mount(&server)
+            .await;
+
+        // The backoff alone would exceed the total delay, but `Retry-After`
+        // takes precedence.
+        let state = crate::DetectionState {
+            github: Options::default()
+                .with_initial_backoff(Duration::from_secs(60))
+                .with_max_total_delay(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let detector = GitHubActions::new(&state)
+            .await
+            .expect("should detect GitHub Actions");
+        let token = detector
+            .detect("test_retry_after")
+            .await
+            .expect("should fetch token after retrying");
+        assert_eq!(token.reveal(), "test-retry-after-token");
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_exceeds_total_delay() {
+        let mut scope = EnvScope::new();
+        let server = MockServer::start().await;
+        token_endpoint_env(&mut scope, &server);
+
+        Mock::given(method("GET"))
+            .respond_with(wiremock::ResponseTemplate::new(503).insert_header("Retry-After", "3600"))
+            .mount(&server)
+            .await;
+
+        let detector = fast_retry_detector(Options::default()).await;
+        assert!(
+            detector
+                .detect("test_retry_after_exceeds_total_delay")
+                .await
+                .is_err()
+        );
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+
+    /// Starts a TCP server that counts the connections it accepts, replies
+    /// to each with `reply`, and then closes it.
+    async fn counting_server(reply: &'static [u8]) -> (std::net::SocketAddr, Arc<AtomicUsize>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        tokio::spawn({
+            let connections = connections.clone();
+            async move {
+                while let Ok((mut stream, _)) = listener.accept().await {
+                    connections.fetch_add(1, Ordering::SeqCst);
+                    let _ = stream.write_all(reply).await;
+                }
+            }
+        });
+        (addr, connections)
+    }
+
+    #[tokio::test]
+    async fn test_retry_connection_error() {
+        let mut scope = EnvScope::new();
+
+        // Connections are accepted and immediately closed, so every TLS
+        // handshake fails: a connection error, which is worth retrying.
+        let (addr, connections) = counting_server(b"").await;
+        scope.setenv("GITHUB_ACTIONS", "true");
+        scope.setenv("ACTIONS_ID_TOKEN_REQUEST_TOKEN", "bogus");
+        scope.setenv("ACTIONS_ID_TOKEN_REQUEST_URL", &format!("https://{addr}"));
+
+        let detector = fast_retry_detector(Options::default().with_max_attempts(4)).await;
+        match detector.detect("test_retry_connection_error").await {
+            Err(super::Error::Request(crate::HttpError::Transport(err))) => {
+                assert!(err.is_connect());
+            }
+            Err(err) => panic!("expected a connection error, got {err:?}"),
+            Ok(_) => panic!("expected a connection error"),
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_no_retry_invalid_response() {
+        let mut scope = EnvScope::new();
+
+        // A server that isn't speaking HTTP: the request fails, but retrying
+        // it won't help.
+        let (addr, connections) = counting_server(b"bupkis\r\n\r\n").await;
+        scope.setenv("GITHUB_ACTIONS", "true");
+        scope.setenv("ACTIONS_ID_TOKEN_REQUEST_TOKEN", "bogus");
+        scope.setenv("ACTIONS_ID_TOKEN_REQUEST_URL", &format!("http://{addr}"));
+
+        let detector = fast_retry_detector(Options::default()).await;
+        assert!(matches!(
+            detector.detect("test_no_retry_invalid_response").await,
+            Err(super::Error::Request(_))
+        ));
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let options = Options::default();
+        for _ in 0..100 {
+            let first = options.backoff(1);
+            assert!(first >= Duration::from_millis(250) && first <= Duration::from_millis(500));
+
+            let third = options.backoff(3);
+            assert!(third >= Duration::from_secs(1) && third <= Duration::from_secs(2));
+
+            assert!(options.backoff(40) <= Duration::from_secs(10));
+        }
     }
 
     #[tokio::test]
@@ -208,7 +955,9 @@ mod tests {
             .await;
 
         let state = Default::default();
-        let detector = GitHubActions::new(&state).expect("should detect GitHub Actions");
+        let detector = GitHubActions::new(&state)
+            .await
+            .expect("should detect GitHub Actions");
         assert!(matches!(
             detector.detect("test_invalid_response").await,
             Err(super::Error::Request(_))
@@ -235,7 +984,9 @@ mod tests {
             .await;
 
         let state = Default::default();
-        let detector = GitHubActions::new(&state).expect("should detect GitHub Actions");
+        let detector = GitHubActions::new(&state)
+            .await
+            .expect("should detect GitHub Actions");
         let token = detector
             .detect("test_ok")
             .await
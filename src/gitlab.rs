@@ -1,16 +1,186 @@
 //! GitLab CI OIDC token detection.
 
-use crate::{DetectionState, DetectionStrategy};
+use std::collections::HashMap;
+
+use crate::{
+    DetectionState, DetectionStrategy, Provider,
+    context::{BuildContext, var},
+};
+
+/// The suffix of GitLab CI ID token environment variables.
+const ID_TOKEN_SUFFIX: &str = "_ID_TOKEN";
 
 /// Possible errors during GitLab CI OIDC token detection.
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     /// The expected environment variable for the ID token was not found.
-    #[error("ID token variable not found: {0}")]
-    Missing(String),
+    #[error(
+        "ID token variable not found: {variable} ({}); \
+         to request it, add this to the job in `.gitlab-ci.yml`:\n\n{snippet}",
+        available_hint(.available)
+    )]
+    Missing {
+        /// The environment variable that was looked for.
+        variable: String,
+        /// The ID token variables that are present, sorted by name.
+        available: Vec<IdTokenVariable>,
+        /// An `id_tokens:` YAML snippet that requests the missing token.
+        snippet: String,
+    },
+    /// The audience can't be unambiguously mapped to an ID token variable.
+    ///
+    /// Other audiences, such as `collides_with`, share the same variable, so
+    /// its token may not be for this audience. This check can be disabled
+    /// with [`Options::with_permissive_audiences`].
+    #[error(
+        "audience {audience:?} is ambiguous on GitLab CI ({reason}): \
+         it shares `{variable}` with other audiences, e.g. {collides_with:?}; \
+         map it to a variable with `GitLabOptions::with_variable`"
+    )]
+    AmbiguousAudience {
+        /// The requested audience.
+        audience: String,
+        /// Why the audience is ambiguous.
+        reason: AmbiguityReason,
+        /// The conventional variable for the audience.
+        variable: String,
+        /// Another audience with the same conventional variable.
+        collides_with: String,
+    },
+}
+
+/// Why an audience can't be unambiguously mapped to a GitLab CI
+/// ID token variable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AmbiguityReason {
+    /// The audience contains non-ASCII characters, which are all
+    /// normalized to `_`.
+    NonAscii,
+    /// The audience has no ASCII alphanumeric characters, so it
+    /// normalizes to only underscores.
+    OnlyUnderscores,
+}
+
+impl std::fmt::Display for AmbiguityReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AmbiguityReason::NonAscii => "it contains non-ASCII characters",
+            AmbiguityReason::OnlyUnderscores => "it normalizes to only underscores",
+        })
+    }
+}
+
+/// An ID token environment variable present in the job.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IdTokenVariable {
+    /// The variable's name, e.g. `SIGSTORE_ID_TOKEN`.
+    pub name: String,
+    /// The audiences in the token's `aud` claim, or empty if the
+    /// token couldn't be decoded.
+    pub audiences: Vec<String>,
+}
+
+impl std::fmt::Display for IdTokenVariable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.audiences.is_empty() {
+            write!(f, "{} (aud: unknown)", self.name)
+        } else {
+            write!(f, "{} (aud: {})", self.name, self.audiences.join(", "))
+        }
+    }
+}
+
+fn available_hint(available: &[IdTokenVariable]) -> String {
+    if available.is_empty() {
+        "no ID token variables are set".into()
+    } else {
+        let available = available
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!("available: {available}")
+    }
+}
+
+/// Options for GitLab CI OIDC token detection.
+#[derive(Clone, Debug)]
+pub struct Options {
+    variables: HashMap<String, String>,
+    audience_scan: bool,
+    permissive_audiences: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            variables: HashMap::new(),
+            audience_scan: true,
+            permissive_audiences: false,
+        }
+    }
+}
+
+impl Options {
+    /// Reads the ID token for `audience` from the environment variable
+    /// `variable`, instead of the conventional `<AUD>_ID_TOKEN`.
+    ///
+    /// This is useful when a job's `id_tokens` already use other names,
+    /// e.g. `PYPI_TOKEN_OIDC`. If `variable` isn't set, the conventional
+    /// variable is still tried.
+    pub fn with_variable(
+        mut self,
+        audience: impl Into<String>,
+        variable: impl Into<String>,
+    ) -> Self {
+        self.variables.insert(audience.into(), variable.into());
+        self
+    }
+
+    /// Sets whether to fall back to scanning all environment variables for
+    /// a JWT whose `aud` claim matches the requested audience, when neither
+    /// a mapped nor a conventional variable is set. The default is `true`.
+    pub fn with_audience_scan(mut self, audience_scan: bool) -> Self {
+        self.audience_scan = audience_scan;
+        self
+    }
+
+    /// Sets whether to look up the conventional variable for audiences that
+    /// normalize ambiguously, i.e. that contain non-ASCII characters or
+    /// normalize to only underscores. The default is `false`, in which case
+    /// such audiences fail with [`Error::AmbiguousAudience`] unless they're
+    /// mapped to a variable or found by the audience scan.
+    pub fn with_permissive_audiences(mut self, permissive_audiences: bool) -> Self {
+        self.permissive_audiences = permissive_audiences;
+        self
+    }
 }
 
-pub(crate) struct GitLabCI;
+/// Returns whether we're running in GitLab CI.
+fn detected() -> bool {
+    // Per GitLab docs, this is exactly "true" when
+    // running in GitLab CI.
+    std::env::var("GITLAB_CI").is_ok_and(|v| v == "true")
+}
+
+/// Returns the build context of the current GitLab CI job.
+///
+/// See: <https://docs.gitlab.com/ci/variables/predefined_variables/>
+pub(crate) fn build_context() -> Option<BuildContext> {
+    detected().then(|| BuildContext {
+        repository: var("CI_PROJECT_PATH"),
+        git_ref: var("CI_COMMIT_REF_NAME"),
+        commit_sha: var("CI_COMMIT_SHA"),
+        run_id: var("CI_PIPELINE_ID"),
+        job_url: var("CI_JOB_URL"),
+        ..BuildContext::new(Provider::GitLabCI)
+    })
+}
+
+pub(crate) struct GitLabCI {
+    options: Options,
+}
 
 impl GitLabCI {
     /// Normalizes an audience string into the format required
@@ -33,18 +203,85 @@ impl GitLabCI {
             })
             .collect()
     }
+
+    /// Returns why `audience` normalizes ambiguously, and another audience
+    /// that normalizes to the same variable, if it does.
+    fn ambiguity(audience: &str) -> Option<(AmbiguityReason, String)> {
+        let reason = if !audience.is_ascii() {
+            AmbiguityReason::NonAscii
+        } else if !audience.chars().any(|c| c.is_ascii_alphanumeric()) {
+            AmbiguityReason::OnlyUnderscores
+        } else {
+            return None;
+        };
+
+        // Swapping the characters that normalize to `_` for other such
+        // characters always yields a distinct audience.
+        let collides_with = audience
+            .chars()
+            .map(|c| match c {
+                c if c.is_ascii_alphanumeric() => c,
+                '_' => '-',
+                _ => '_',
+            })
+            .collect();
+
+        Some((reason, collides_with))
+    }
+
+    /// Returns the ID token variables present in the environment,
+    /// with their decoded audiences.
+    fn available_variables() -> Vec<IdTokenVariable> {
+        let mut available = crate::utf8_env_vars()
+            .filter(|(name, _)| {
+                name.len() > ID_TOKEN_SUFFIX.len() && name.ends_with(ID_TOKEN_SUFFIX)
+            })
+            .map(|(name, token)| IdTokenVariable {
+                name,
+                audiences: crate::jwt::decode_claims(&token)
+                    .map(|claims| {
+                        crate::jwt::audiences(&claims)
+                            .into_iter()
+                            .map(str::to_string)
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+            })
+            .collect::<Vec<_>>();
+        available.sort_by(|a, b| a.name.cmp(&b.name));
+        available
+    }
+
+    /// Returns the value of the first environment variable (by name) that
+    /// holds a JWT for `audience`.
+    fn scan(audience: &str) -> Option<String> {
+        let mut vars = crate::utf8_env_vars()
+            .filter(|(_, value)| crate::jwt::has_audience(value, audience))
+            .collect::<Vec<_>>();
+        vars.sort();
+        vars.into_iter().next().map(|(_, token)| token)
+    }
+
+    /// Returns an `id_tokens:` snippet for `.gitlab-ci.yml` that requests
+    /// `variable` for `audience`.
+    fn snippet(variable: &str, audience: &str) -> String {
+        // JSON strings are valid YAML, and quoting sidesteps any special
+        // characters in the audience.
+        let audience =
+            serde_json::to_string(audience).expect("impossible: string serialization failed");
+        format!("id_tokens:\n  {variable}:\n    aud: {audience}")
+    }
 }
 
 impl DetectionStrategy for GitLabCI {
     type Error = Error;
 
-    fn new(_state: &DetectionState) -> Option<Self> {
-        std::env::var("GITLAB_CI")
-            .ok()
-            // Per GitLab docs, this is exactly "true" when
-            // running in GitLab CI.
-            .filter(|v| v == "true")
-            .map(|_| GitLabCI)
+    const PROVIDER: Provider = Provider::GitLabCI;
+
+    async fn new(state: &DetectionState) -> Option<Self> {
+        detected().then(|| GitLabCI {
+            options: state.gitlab.clone(),
+        })
     }
 
     /// On GitLab CI, the OIDC token URL is provided via an environment variable.
@@ -54,11 +291,50 @@ impl DetectionStrategy for GitLabCI {
     /// As an example, audience "sigstore" would require variable SIGSTORE_ID_TOKEN,
     /// and audience "http://test.audience" would require variable
     /// HTTP___TEST_AUDIENCE_ID_TOKEN.
+    ///
+    /// A variable explicitly mapped to the audience in [`Options`] is tried
+    /// first. If neither it nor the conventional variable is set, we fall back
+    /// to any variable holding a JWT for the audience.
+    ///
+    /// The conventional variable isn't used for audiences that normalize
+    /// ambiguously (see [`AmbiguityReason`]), unless that's permitted
+    /// in [`Options`].
     async fn detect(&self, audience: &str) -> Result<crate::IdToken, Self::Error> {
-        let normalized_audience = Self::normalized_audience(audience);
+        let conventional = format!(
+            "{normalized_audience}{ID_TOKEN_SUFFIX}",
+            normalized_audience = Self::normalized_audience(audience)
+        );
+        let mapped = self.options.variables.get(audience);
+        let ambiguity = Self::ambiguity(audience).filter(|_| !self.options.permissive_audiences);
+
+        let token = mapped
+            .into_iter()
+            .chain(ambiguity.is_none().then_some(&conventional))
+            .find_map(|var_name| std::env::var(var_name).ok())
+            .or_else(|| {
+                self.options
+                    .audience_scan
+                    .then(|| Self::scan(audience))
+                    .flatten()
+            });
 
-        let var_name = format!("{normalized_audience}_ID_TOKEN");
-        let token = std::env::var(&var_name).map_err(|_| Error::Missing(var_name))?;
+        let Some(token) = token else {
+            if let (None, Some((reason, collides_with))) = (mapped, ambiguity) {
+                return Err(Error::AmbiguousAudience {
+                    audience: audience.into(),
+                    reason,
+                    variable: conventional,
+                    collides_with,
+                });
+            }
+
+            let var_name = mapped.unwrap_or(&conventional);
+            return Err(Error::Missing {
+                snippet: Self::snippet(var_name, audience),
+                available: Self::available_variables(),
+                variable: var_name.clone(),
+            });
+        };
 
         Ok(crate::IdToken(token.into()))
     }
@@ -66,9 +342,28 @@ impl DetectionStrategy for GitLabCI {
 
 #[cfg(test)]
 mod tests {
-    use crate::{DetectionStrategy as _, gitlab::Error, tests::EnvScope};
+    use crate::{DetectionStrategy as _, gitlab::Error, jwt::tests::fake_jwt, tests::EnvScope};
 
-    use super::GitLabCI;
+    use super::{AmbiguityReason, GitLabCI, IdTokenVariable, Options};
+
+    /// Returns a detector with the given options, in a GitLab CI environment
+    /// without any ID token variables.
+    async fn detector(scope: &mut EnvScope, options: Options) -> GitLabCI {
+        for (name, value) in crate::utf8_env_vars() {
+            if name.ends_with("_ID_TOKEN") || crate::jwt::decode_claims(&value).is_some() {
+                scope.unsetenv(&name);
+            }
+        }
+        scope.setenv("GITLAB_CI", "true");
+
+        let state = crate::DetectionState {
+            gitlab: options,
+            ..Default::default()
+        };
+        GitLabCI::new(&state)
+            .await
+            .expect("should detect GitLab CI")
+    }
 
     #[test]
     fn test_normalized_audience() {
@@ -77,9 +372,8 @@ mod tests {
             ("http://test.audience", "HTTP___TEST_AUDIENCE"),
             ("my-audience_123", "MY_AUDIENCE_123"),
             ("Audience With Spaces!", "AUDIENCE_WITH_SPACES_"),
-            // TODO(ww): This mirrors what `id` does, but maybe we should
-            // reject audiences with non-ASCII characters? Or reject those
-            // that normalize to only underscores?
+            // This mirrors what `id` does; such audiences are rejected
+            // by `detect` unless permitted (see `test_ambiguous_audience`).
             ("😭", "_"),
             ("😭😭😭", "___"),
         ];
@@ -94,7 +388,7 @@ mod tests {
         let mut scope = EnvScope::new();
         scope.setenv("GITLAB_CI", "true");
 
-        assert!(GitLabCI::new(&Default::default()).is_some())
+        assert!(GitLabCI::new(&Default::default()).await.is_some())
     }
 
     #[tokio::test]
@@ -102,7 +396,7 @@ mod tests {
         let mut scope = EnvScope::new();
         scope.unsetenv("GITLAB_CI");
 
-        assert!(GitLabCI::new(&Default::default()).is_none());
+        assert!(GitLabCI::new(&Default::default()).await.is_none());
     }
 
     #[tokio::test]
@@ -111,7 +405,7 @@ mod tests {
             let mut scope = EnvScope::new();
             scope.setenv("GITLAB_CI", value);
 
-            assert!(GitLabCI::new(&Default::default()).is_none());
+            assert!(GitLabCI::new(&Default::default()).await.is_none());
         }
     }
 
@@ -121,21 +415,209 @@ mod tests {
         scope.setenv("GITLAB_CI", "true");
         scope.setenv("WRONG_ID_TOKEN", "sometoken");
 
-        let detector = GitLabCI::new(&Default::default()).expect("should detect GitLab CI");
+        let detector = GitLabCI::new(&Default::default())
+            .await
+            .expect("should detect GitLab CI");
         assert!(matches!(
             detector.detect("bupkis").await,
-            Err(Error::Missing(_))
+            Err(Error::Missing { variable, .. }) if variable == "BUPKIS_ID_TOKEN"
         ));
     }
 
+    #[tokio::test]
+    async fn test_missing_lists_available() {
+        let mut scope = EnvScope::new();
+        let detector = detector(&mut scope, Options::default().with_audience_scan(false)).await;
+        scope.setenv(
+            "SIGSTORE_ID_TOKEN",
+            &fake_jwt(serde_json::json!({"aud": "sigstore"})),
+        );
+        scope.setenv(
+            "VAULT_ID_TOKEN",
+            &fake_jwt(serde_json::json!({"aud": ["https://vault.example.com", "vault"]})),
+        );
+        scope.setenv("GARBAGE_ID_TOKEN", "not-a-jwt");
+        // Variables that aren't UTF-8 are skipped, rather than panicking.
+        #[cfg(unix)]
+        scope.setenv_os(
+            "BINARY_ID_TOKEN",
+            std::os::unix::ffi::OsStrExt::from_bytes(b"\xff\xfe"),
+        );
+
+        let Err(err) = detector.detect("https://pypi.org").await else {
+            panic!("expected missing token error");
+        };
+        let Error::Missing {
+            variable,
+            available,
+            snippet,
+        } = &err
+        else {
+            panic!("expected missing token error, got {err:?}");
+        };
+
+        assert_eq!(variable, "HTTPS___PYPI_ORG_ID_TOKEN");
+        assert_eq!(
+            available,
+            &[
+                IdTokenVariable {
+                    name: "GARBAGE_ID_TOKEN".into(),
+                    audiences: vec![],
+                },
+                IdTokenVariable {
+                    name: "SIGSTORE_ID_TOKEN".into(),
+                    audiences: vec!["sigstore".into()],
+                },
+                IdTokenVariable {
+                    name: "VAULT_ID_TOKEN".into(),
+                    audiences: vec!["https://vault.example.com".into(), "vault".into()],
+                },
+            ]
+        );
+        assert_eq!(
+            snippet,
+            "id_tokens:\n  HTTPS___PYPI_ORG_ID_TOKEN:\n    aud: \"https://pypi.org\""
+        );
+        assert_eq!(
+            err.to_string(),
+            "ID token variable not found: HTTPS___PYPI_ORG_ID_TOKEN \
+             (available: GARBAGE_ID_TOKEN (aud: unknown), SIGSTORE_ID_TOKEN (aud: sigstore), \
+             VAULT_ID_TOKEN (aud: https://vault.example.com, vault)); \
+             to request it, add this to the job in `.gitlab-ci.yml`:\n\n\
+             id_tokens:\n  HTTPS___PYPI_ORG_ID_TOKEN:\n    aud: \"https://pypi.org\""
+        );
+    }
+
     #[tokio::test]
     async fn test_ok() {
         let mut scope = EnvScope::new();
         scope.setenv("GITLAB_CI", "true");
         scope.setenv("BUPKIS_ID_TOKEN", "sometoken");
 
-        let detector = GitLabCI::new(&Default::default()).expect("should detect GitLab CI");
+        let detector = GitLabCI::new(&Default::default())
+            .await
+            .expect("should detect GitLab CI");
         let token = detector.detect("bupkis").await.expect("should fetch token");
         assert_eq!(token.reveal(), "sometoken");
     }
+
+    #[tokio::test]
+    async fn test_mapped_variable() {
+        let mut scope = EnvScope::new();
+        let detector = detector(
+            &mut scope,
+            Options::default().with_variable("pypi", "PYPI_TOKEN_OIDC"),
+        )
+        .await;
+
+        scope.setenv("PYPI_TOKEN_OIDC", "mapped");
+        scope.setenv("PYPI_ID_TOKEN", "conventional");
+        let token = detector.detect("pypi").await.expect("should fetch token");
+        assert_eq!(token.reveal(), "mapped");
+
+        // The conventional variable is still tried.
+        scope.unsetenv("PYPI_TOKEN_OIDC");
+        let token = detector.detect("pypi").await.expect("should fetch token");
+        assert_eq!(token.reveal(), "conventional");
+
+        // Errors name the mapped variable.
+        scope.unsetenv("PYPI_ID_TOKEN");
+        assert!(matches!(
+            detector.detect("pypi").await,
+            Err(Error::Missing { variable, .. }) if variable == "PYPI_TOKEN_OIDC"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_audience_scan() {
+        let mut scope = EnvScope::new();
+        let detector = detector(&mut scope, Options::default()).await;
+
+        let token = fake_jwt(serde_json::json!({"aud": ["other", "pypi"]}));
+        // Variables that aren't UTF-8 are skipped, rather than panicking.
+        #[cfg(unix)]
+        scope.setenv_os(
+            "BINARY_OIDC_JWT",
+            std::os::unix::ffi::OsStrExt::from_bytes(b"\xff\xfe"),
+        );
+        scope.setenv("SOME_OIDC_JWT", &token);
+        scope.setenv(
+            "OTHER_OIDC_JWT",
+            &fake_jwt(serde_json::json!({"aud": "other"})),
+        );
+
+        let found = detector.detect("pypi").await.expect("should fetch token");
+        assert_eq!(found.reveal(), token);
+
+        let detector = GitLabCI {
+            options: Options::default().with_audience_scan(false),
+        };
+        assert!(matches!(
+            detector.detect("pypi").await,
+            Err(Error::Missing { .. })
+        ));
+    }
+
+    #[test]
+    fn test_ambiguity() {
+        assert_eq!(GitLabCI::ambiguity("sigstore"), None);
+        assert_eq!(GitLabCI::ambiguity("http://test.audience"), None);
+        assert_eq!(
+            GitLabCI::ambiguity("pypí"),
+            Some((AmbiguityReason::NonAscii, "pyp_".into()))
+        );
+        assert_eq!(
+            GitLabCI::ambiguity("😭"),
+            Some((AmbiguityReason::NonAscii, "_".into()))
+        );
+        assert_eq!(
+            GitLabCI::ambiguity("://"),
+            Some((AmbiguityReason::OnlyUnderscores, "___".into()))
+        );
+        assert_eq!(
+            GitLabCI::ambiguity("__"),
+            Some((AmbiguityReason::OnlyUnderscores, "--".into()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ambiguous_audience() {
+        let mut scope = EnvScope::new();
+        let detector = detector(&mut scope, Options::default()).await;
+        scope.setenv("___ID_TOKEN", "sometoken");
+
+        let Err(err) = detector.detect("😭😭").await else {
+            panic!("expected ambiguous audience error");
+        };
+        assert!(matches!(
+            &err,
+            Error::AmbiguousAudience {
+                reason: AmbiguityReason::NonAscii,
+                variable,
+                collides_with,
+                ..
+            } if variable == "___ID_TOKEN" && collides_with == "__"
+        ));
+
+        // Explicitly mapped audiences aren't ambiguous.
+        let detector = GitLabCI {
+            options: Options::default().with_variable("😭😭", "___ID_TOKEN"),
+        };
+        let token = detector.detect("😭😭").await.expect("should fetch token");
+        assert_eq!(token.reveal(), "sometoken");
+
+        // Nor are tokens found by their `aud`.
+        scope.setenv("EMOJI_OIDC", &fake_jwt(serde_json::json!({"aud": "😭😭"})));
+        let detector = GitLabCI {
+            options: Options::default(),
+        };
+        assert!(detector.detect("😭😭").await.is_ok());
+        scope.unsetenv("EMOJI_OIDC");
+
+        let detector = GitLabCI {
+            options: Options::default().with_permissive_audiences(true),
+        };
+        let token = detector.detect("😭😭").await.expect("should fetch token");
+        assert_eq!(token.reveal(), "sometoken");
+    }
 }
@@ -0,0 +1,258 @@
+//! Helpers for HTTP-based detection strategies.
+
+use reqwest::StatusCode;
+
+/// The maximum length of a response body (or of the error message in it)
+/// included in an error, in characters.
+const BODY_MAX_LEN: usize = 512;
+
+/// A failed HTTP request.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The request couldn't be sent, or its response couldn't be read.
+    #[error(transparent)]
+    Transport(#[from] reqwest_middleware::Error),
+    /// The server responded with an unsuccessful status.
+    #[error("{url} returned {status}: {}", message.as_deref().unwrap_or(body))]
+    Status {
+        /// The response's status.
+        status: StatusCode,
+        /// The request's URL, without any query string or credentials.
+        url: String,
+        /// The response's body, redacted and truncated.
+        body: String,
+        /// The error message in the body, if the provider uses a known
+        /// error format (e.g. Google's `error.message`), redacted and
+        /// truncated.
+        message: Option<String>,
+    },
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Error::Transport(err.into())
+    }
+}
+
+impl Error {
+    /// Returns the response's status, if the server responded.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Error::Transport(err) => err.status(),
+            Error::Status { status, .. } => Some(*status),
+        }
+    }
+}
+
+/// The format of a provider's error responses.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum ErrorFormat {
+    /// No known format; only the raw body is reported.
+    Plain,
+    /// A top-level `message`, as used by GitHub and Buildkite.
+    Message,
+    /// Google's API errors (`error.message`), or OAuth 2.0 errors
+    /// (`error` and `error_description`) from its token endpoints.
+    Google,
+}
+
+impl ErrorFormat {
+    /// Extracts the error message from a response body, if it's in this format.
+    fn message(self, body: &str) -> Option<String> {
+        let body = serde_json::from_str::<serde_json::Value>(body).ok()?;
+
+        let message = match self {
+            ErrorFormat::Plain => None,
+            ErrorFormat::Message => body["message"].as_str().map(str::to_string),
+            ErrorFormat::Google => match &body["error"] {
+                serde_json::Value::Object(error) => error
+                    .get("message")
+                    .and_then(|m| m.as_str())
+                    .map(str::to_string),
+                serde_json::Value::String(error) => {
+                    Some(match body["error_description"].as_str() {
+                        Some(description) => format!("{error}: {description}"),
+                        None => error.clone(),
+                    })
+                }
+                _ => None,
+            },
+        };
+
+        message.filter(|message| !message.is_empty())
+    }
+}
+
+/// Checks HTTP responses for unsuccessful statuses.
+pub(crate) trait ResponseExt: Sized {
+    /// Returns the response if its status is successful, or an
+    /// [`Error::Status`] describing it otherwise.
+    async fn check(self, format: ErrorFormat) -> Result<Self, Error>;
+}
+
+impl ResponseExt for reqwest::Response {
+    async fn check(self, format: ErrorFormat) -> Result<Self, Error> {
+        let status = self.status();
+        if status.is_success() {
+            return Ok(self);
+        }
+
+        let mut url = self.url().clone();
+        url.set_query(None);
+        url.set_fragment(None);
+        // These can only fail for URLs that can't have credentials anyway.
+        let _ = url.set_username("");
+        let _ = url.set_password(None);
+
+        let body = crate::redact::redact(self.text().await.unwrap_or_default().trim());
+        let message = format.message(&body).map(|message| truncate(&message));
+
+        Err(Error::Status {
+            status,
+            url: url.to_string(),
+            body: truncate(&body),
+            message,
+        })
+    }
+}
+
+fn truncate(body: &str) -> String {
+    if body.chars().count() > BODY_MAX_LEN {
+        let truncated = body.chars().take(BODY_MAX_LEN).collect::<String>();
+        format!("{truncated}…")
+    } else {
+        body.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+    use wiremock::{Mock, MockServer, ResponseTemplate, matchers::method};
+
+    use super::{Error, ErrorFormat, ResponseExt as _};
+
+    async fn check(response: ResponseTemplate, format: ErrorFormat) -> Result<(), Error> {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(response)
+            .mount(&server)
+            .await;
+
+        reqwest::get(format!("{}/some/path?secret=hunter2#frag", server.uri()))
+            .await
+            .unwrap()
+            .check(format)
+            .await
+            .map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn test_check_ok() {
+        assert!(
+            check(ResponseTemplate::new(200), ErrorFormat::Plain)
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_status() {
+        let err = check(
+            ResponseTemplate::new(503).set_body_string("upstream unavailable\n"),
+            ErrorFormat::Plain,
+        )
+        .await
+        .unwrap_err();
+
+        let Error::Status {
+            status,
+            url,
+            body,
+            message,
+        } = &err
+        else {
+            panic!("expected status error, got {err:?}");
+        };
+        assert_eq!(*status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(url.ends_with("/some/path"), "{url}");
+        assert_eq!(body, "upstream unavailable");
+        assert_eq!(*message, None);
+        assert_eq!(err.status(), Some(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(!err.to_string().contains("hunter2"));
+    }
+
+    #[tokio::test]
+    async fn test_check_google_error() {
+        let err = check(
+            ResponseTemplate::new(403).set_body_json(serde_json::json!({
+                "error": {
+                    "code": 403,
+                    "message": "Permission 'iam.serviceAccounts.getOpenIdToken' denied",
+                    "status": "PERMISSION_DENIED",
+                }
+            })),
+            ErrorFormat::Google,
+        )
+        .await
+        .unwrap_err();
+
+        assert!(err.to_string().ends_with(
+            "returned 403 Forbidden: Permission 'iam.serviceAccounts.getOpenIdToken' denied"
+        ));
+
+        let err = check(
+            ResponseTemplate::new(400).set_body_json(serde_json::json!({
+                "error": "invalid_grant",
+                "error_description": "Token has been expired or revoked.",
+            })),
+            ErrorFormat::Google,
+        )
+        .await
+        .unwrap_err();
+
+        assert!(err.to_string().ends_with(
+            "returned 400 Bad Request: invalid_grant: Token has been expired or revoked."
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_check_redacted_truncated() {
+        let body = format!(
+            "bad token eyJhbGciOiJSUzI1NiJ9.eyJhdWQiOiJ4In0.c2ln {}",
+            "x".repeat(1000)
+        );
+        let err = check(
+            ResponseTemplate::new(401).set_body_string(body),
+            ErrorFormat::Message,
+        )
+        .await
+        .unwrap_err();
+
+        let Error::Status { body, .. } = err else {
+            panic!("expected status error");
+        };
+        assert!(body.starts_with("bad token [REDACTED] xxx"));
+        assert_eq!(body.chars().count(), 513);
+
+        let err = check(
+            ResponseTemplate::new(400).set_body_json(serde_json::json!({
+                "message": format!("bad token eyJhbGciOiJSUzI1NiJ9.eyJhdWQiOiJ4In0.c2ln {}", "y".repeat(1000)),
+            })),
+            ErrorFormat::Message,
+        )
+        .await
+        .unwrap_err();
+
+        let Error::Status {
+            message: Some(message),
+            ..
+        } = &err
+        else {
+            panic!("expected status error with a message");
+        };
+        assert!(message.starts_with("bad token [REDACTED] yyy"));
+        assert_eq!(message.chars().count(), 513);
+        assert!(err.to_string().ends_with("yyy…"));
+    }
+}
@@ -0,0 +1,362 @@
+//! Normalized workload identities from ID token claims.
+
+use serde_json::Value;
+
+use crate::{Claims, IdToken, Provider};
+
+/// The issuer of GitHub Actions ID tokens. Enterprises with a customized
+/// issuer append their slug to it.
+const GITHUB_ISSUER: &str = "https://token.actions.githubusercontent.com";
+
+/// The issuer of GitLab.com ID tokens. Self-managed instances use their own
+/// URL, so their tokens are recognized by their claims instead.
+const GITLAB_ISSUER: &str = "https://gitlab.com";
+
+/// The issuer of Buildkite ID tokens.
+const BUILDKITE_ISSUER: &str = "https://agent.buildkite.com";
+
+/// The issuer of CircleCI ID tokens; per-organization issuers append
+/// `/org/<org-id>` to it.
+const CIRCLECI_ISSUER: &str = "https://oidc.circleci.com";
+
+/// The issuers of Google ID tokens.
+const GOOGLE_ISSUERS: &[&str] = &["https://accounts.google.com", "accounts.google.com"];
+
+/// The workload an ID token was issued to, normalized across providers.
+///
+/// Providers describe the same concepts with different claims, e.g. GitHub
+/// Actions' `repository` and GitLab CI's `project_path`. This maps them to
+/// common fields, which are `None` when the provider has no equivalent.
+/// The token's raw claims remain available in [`claims`](Self::claims).
+///
+/// Nothing here verifies the token's signature: only use this to describe
+/// tokens you've obtained yourself, not to authenticate others.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+#[non_exhaustive]
+pub struct WorkloadIdentity {
+    /// The provider that issued the token, if it could be recognized
+    /// from its issuer (or, for self-managed GitLab, its claims).
+    pub provider: Option<Provider>,
+    /// The token's issuer (`iss`).
+    pub issuer: Option<String>,
+    /// The token's subject (`sub`).
+    pub subject: Option<String>,
+    /// The source repository, e.g. `owner/repo`.
+    ///
+    /// This is the `repository` claim on GitHub Actions, `project_path`
+    /// on GitLab CI, and `oidc.circleci.com/vcs-origin` (or, failing that,
+    /// `oidc.circleci.com/project-id`) on CircleCI.
+    pub repository: Option<String>,
+    /// The Git ref being built.
+    #[serde(rename = "ref")]
+    pub git_ref: Option<String>,
+    /// The pipeline definition: GitHub Actions' `workflow_ref`, GitLab CI's
+    /// `ci_config_ref_uri`, Buildkite's `<organization>/<pipeline>` slugs,
+    /// or CircleCI's project ID.
+    pub pipeline: Option<String>,
+    /// What triggered the build, e.g. GitHub Actions' `event_name`, GitLab
+    /// CI's `pipeline_source` or Buildkite's `build_source`.
+    pub trigger: Option<String>,
+    /// The kind of runner the build ran on, e.g. `github-hosted` or
+    /// `self-hosted`, or `compute-engine` for GCP instances.
+    pub runner_environment: Option<String>,
+    /// The GCP service account the token was issued to (`email`).
+    pub service_account: Option<String>,
+    /// All of the token's claims.
+    pub claims: Claims,
+}
+
+impl WorkloadIdentity {
+    /// Builds a workload identity from an ID token's decoded claims.
+    pub fn from_claims(claims: Claims) -> Self {
+        let issuer = string(&claims, "iss");
+        let provider = issuer
+            .as_deref()
+            .and_then(|issuer| provider(issuer, &claims));
+
+        let mut identity = WorkloadIdentity {
+            provider,
+            issuer,
+            subject: string(&claims, "sub"),
+            repository: None,
+            git_ref: None,
+            pipeline: None,
+            trigger: None,
+            runner_environment: None,
+            service_account: None,
+            claims: Claims::new(),
+        };
+
+        match provider {
+            // https://docs.github.com/en/actions/reference/security/oidc#oidc-token-claims
+            Some(Provider::GitHubActions) => {
+                identity.repository = string(&claims, "repository");
+                identity.git_ref = string(&claims, "ref");
+                identity.pipeline = string(&claims, "workflow_ref");
+                identity.trigger = string(&claims, "event_name");
+                identity.runner_environment = string(&claims, "runner_environment");
+            }
+            // https://docs.gitlab.com/ci/secrets/id_token_authentication/#token-payload
+            Some(Provider::GitLabCI) => {
+                identity.repository = string(&claims, "project_path");
+                identity.git_ref = string(&claims, "ref");
+                identity.pipeline = string(&claims, "ci_config_ref_uri");
+                identity.trigger = string(&claims, "pipeline_source");
+                identity.runner_environment = string(&claims, "runner_environment");
+            }
+            // https://buildkite.com/docs/agent/v3/cli-oidc#claims
+            Some(Provider::Buildkite) => {
+                identity.git_ref =
+                    string(&claims, "build_tag").or_else(|| string(&claims, "build_branch"));
+                identity.pipeline = match (
+                    string(&claims, "organization_slug"),
+                    string(&claims, "pipeline_slug"),
+                ) {
+                    (Some(organization), Some(pipeline)) => {
+                        Some(format!("{organization}/{pipeline}"))
+                    }
+                    (None, pipeline) => pipeline,
+                    (Some(_), None) => None,
+                };
+                identity.trigger = string(&claims, "build_source");
+                identity.runner_environment = string(&claims, "runner_environment");
+            }
+            // https://circleci.com/docs/openid-connect-tokens/#format-of-the-openid-connect-id-token
+            Some(Provider::CircleCI) => {
+                let project_id = string(&claims, "oidc.circleci.com/project-id");
+                identity.repository =
+                    string(&claims, "oidc.circleci.com/vcs-origin").or_else(|| project_id.clone());
+                identity.git_ref = string(&claims, "oidc.circleci.com/vcs-ref");
+                identity.pipeline = project_id;
+            }
+            // https://cloud.google.com/compute/docs/instances/verifying-instance-identity#payload
+            Some(Provider::Gcp) => {
+                identity.service_account = string(&claims, "email");
+                if claims
+                    .get("google")
+                    .and_then(|google| google.get("compute_engine"))
+                    .is_some()
+                {
+                    identity.runner_environment = Some("compute-engine".into());
+                }
+            }
+            None => {}
+        }
+
+        identity.claims = claims;
+        identity
+    }
+
+    /// Builds a workload identity from an ID token, or returns `None` if
+    /// the token isn't a JWT.
+    pub fn from_token(token: &IdToken) -> Option<Self> {
+        crate::jwt::decode_claims(token.reveal()).map(Self::from_claims)
+    }
+}
+
+/// Returns the provider that issued a token, from its issuer and claims.
+fn provider(issuer: &str, claims: &Claims) -> Option<Provider> {
+    let issuer = issuer.trim_end_matches('/');
+
+    if issuer == GITHUB_ISSUER || issuer.starts_with(&format!("{GITHUB_ISSUER}/")) {
+        Some(Provider::GitHubActions)
+    } else if issuer == GITLAB_ISSUER
+        || (claims.contains_key("project_path") && claims.contains_key("pipeline_source"))
+    {
+        Some(Provider::GitLabCI)
+    } else if issuer == BUILDKITE_ISSUER {
+        Some(Provider::Buildkite)
+    } else if issuer == CIRCLECI_ISSUER || issuer.starts_with(&format!("{CIRCLECI_ISSUER}/org/")) {
+        Some(Provider::CircleCI)
+    } else if GOOGLE_ISSUERS.contains(&issuer) {
+        Some(Provider::Gcp)
+    } else {
+        None
+    }
+}
+
+/// Returns a claim's value if it's a non-empty string.
+fn string(claims: &Claims, name: &str) -> Option<String> {
+    claims
+        .get(name)
+        .and_then(Value::as_str)
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{IdToken, Provider, jwt::tests::fake_jwt};
+
+    use super::WorkloadIdentity;
+
+    fn identity(claims: serde_json::Value) -> WorkloadIdentity {
+        let serde_json::Value::Object(claims) = claims else {
+            panic!("claims must be an object");
+        };
+        WorkloadIdentity::from_claims(claims)
+    }
+
+    #[test]
+    fn test_github_actions() {
+        let identity = identity(json!({
+            "iss": "https://token.actions.githubusercontent.com",
+            "sub": "repo:octo-org/octo-repo:ref:refs/heads/main",
+            "repository": "octo-org/octo-repo",
+            "ref": "refs/heads/main",
+            "workflow_ref": "octo-org/octo-repo/.github/workflows/release.yml@refs/heads/main",
+            "event_name": "push",
+            "runner_environment": "github-hosted",
+        }));
+
+        assert_eq!(identity.provider, Some(Provider::GitHubActions));
+        assert_eq!(identity.repository.as_deref(), Some("octo-org/octo-repo"));
+        assert_eq!(identity.git_ref.as_deref(), Some("refs/heads/main"));
+        assert_eq!(
+            identity.pipeline.as_deref(),
+            Some("octo-org/octo-repo/.github/workflows/release.yml@refs/heads/main")
+        );
+        assert_eq!(identity.trigger.as_deref(), Some("push"));
+        assert_eq!(
+            identity.runner_environment.as_deref(),
+            Some("github-hosted")
+        );
+        assert_eq!(identity.claims["event_name"], "push");
+
+        // Enterprises can customize the issuer.
+        let identity = identity_for_issuer("https://token.actions.githubusercontent.com/octo-ent");
+        assert_eq!(identity.provider, Some(Provider::GitHubActions));
+    }
+
+    fn identity_for_issuer(issuer: &str) -> WorkloadIdentity {
+        identity(json!({ "iss": issuer }))
+    }
+
+    #[test]
+    fn test_gitlab_ci() {
+        let claims = json!({
+            "iss": "https://gitlab.example.com",
+            "sub": "project_path:mygroup/myproject:ref_type:branch:ref:main",
+            "project_path": "mygroup/myproject",
+            "ref": "main",
+            "ci_config_ref_uri": "gitlab.example.com/mygroup/myproject//.gitlab-ci.yml@refs/heads/main",
+            "pipeline_source": "push",
+            "runner_environment": "self-hosted",
+        });
+
+        // Self-managed instances are recognized by their claims.
+        let identity = identity(claims);
+        assert_eq!(identity.provider, Some(Provider::GitLabCI));
+        assert_eq!(identity.repository.as_deref(), Some("mygroup/myproject"));
+        assert_eq!(identity.git_ref.as_deref(), Some("main"));
+        assert_eq!(
+            identity.pipeline.as_deref(),
+            Some("gitlab.example.com/mygroup/myproject//.gitlab-ci.yml@refs/heads/main")
+        );
+        assert_eq!(identity.trigger.as_deref(), Some("push"));
+        assert_eq!(identity.runner_environment.as_deref(), Some("self-hosted"));
+
+        assert_eq!(
+            identity_for_issuer("https://gitlab.com").provider,
+            Some(Provider::GitLabCI)
+        );
+    }
+
+    #[test]
+    fn test_buildkite() {
+        let identity = identity(json!({
+            "iss": "https://agent.buildkite.com",
+            "sub": "organization:acme-inc:pipeline:my-project:ref:refs/heads/main:commit:83a20ec:step:build",
+            "organization_slug": "acme-inc",
+            "pipeline_slug": "my-project",
+            "build_branch": "main",
+            "build_source": "webhook",
+        }));
+
+        assert_eq!(identity.provider, Some(Provider::Buildkite));
+        assert_eq!(identity.repository, None);
+        assert_eq!(identity.git_ref.as_deref(), Some("main"));
+        assert_eq!(identity.pipeline.as_deref(), Some("acme-inc/my-project"));
+        assert_eq!(identity.trigger.as_deref(), Some("webhook"));
+    }
+
+    #[test]
+    fn test_circleci() {
+        let identity = identity(json!({
+            "iss": "https://oidc.circleci.com/org/0f2ab3f6-bd1a-4ad0-a9c6-27bc8e1f3e6c",
+            "sub": "org/0f2ab3f6-bd1a-4ad0-a9c6-27bc8e1f3e6c/project/5d1c7fd1-0b5f-4d31-9c6e-1b6a57d3f0c4/user/6b7f0d8e-3c1f-4e7a-9a4b-2d5c8e1f0a3b",
+            "oidc.circleci.com/project-id": "5d1c7fd1-0b5f-4d31-9c6e-1b6a57d3f0c4",
+            "oidc.circleci.com/vcs-origin": "github.com/acme-inc/my-project",
+            "oidc.circleci.com/vcs-ref": "refs/heads/main",
+        }));
+
+        assert_eq!(identity.provider, Some(Provider::CircleCI));
+        assert_eq!(
+            identity.repository.as_deref(),
+            Some("github.com/acme-inc/my-project")
+        );
+        assert_eq!(identity.git_ref.as_deref(), Some("refs/heads/main"));
+        assert_eq!(
+            identity.pipeline.as_deref(),
+            Some("5d1c7fd1-0b5f-4d31-9c6e-1b6a57d3f0c4")
+        );
+    }
+
+    #[test]
+    fn test_gcp() {
+        let identity = identity(json!({
+            "iss": "https://accounts.google.com",
+            "sub": "111111111111111111111",
+            "email": "sa@example.iam.gserviceaccount.com",
+            "google": {
+                "compute_engine": {
+                    "project_id": "my-project",
+                    "zone": "us-central1-a",
+                }
+            }
+        }));
+
+        assert_eq!(identity.provider, Some(Provider::Gcp));
+        assert_eq!(
+            identity.service_account.as_deref(),
+            Some("sa@example.iam.gserviceaccount.com")
+        );
+        assert_eq!(
+            identity.runner_environment.as_deref(),
+            Some("compute-engine")
+        );
+        assert_eq!(identity.repository, None);
+    }
+
+    #[test]
+    fn test_unknown_issuer() {
+        let identity = identity_for_issuer("https://issuer.example.com");
+        assert_eq!(identity.provider, None);
+        assert_eq!(
+            identity.issuer.as_deref(),
+            Some("https://issuer.example.com")
+        );
+        assert_eq!(identity.repository, None);
+    }
+
+    #[test]
+    fn test_from_token() {
+        let token = IdToken(
+            fake_jwt(json!({
+                "iss": "https://agent.buildkite.com",
+                "pipeline_slug": "my-project",
+            }))
+            .into(),
+        );
+        let identity = WorkloadIdentity::from_token(&token).expect("should decode token");
+        assert_eq!(identity.provider, Some(Provider::Buildkite));
+        assert_eq!(identity.pipeline.as_deref(), Some("my-project"));
+
+        assert_eq!(
+            WorkloadIdentity::from_token(&IdToken("not-a-jwt".to_string().into())),
+            None
+        );
+    }
+}
@@ -0,0 +1,142 @@
+//! Minimal JWT inspection.
+//!
+//! Nothing here verifies signatures: these helpers only look inside tokens
+//! we've already obtained, e.g. to check which audience they're for.
+
+use base64::Engine as _;
+use serde_json::{Map, Value};
+
+/// Decodes a JWT's claims (i.e. its payload), if `token` looks like a JWT.
+pub(crate) fn decode_claims(token: &str) -> Option<Map<String, Value>> {
+    let mut segments = token.trim().split('.');
+    let (Some(_header), Some(payload), Some(_signature), None) = (
+        segments.next(),
+        segments.next(),
+        segments.next(),
+        segments.next(),
+    ) else {
+        return None;
+    };
+
+    let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .ok()?;
+
+    serde_json::from_slice(&payload).ok()
+}
+
+/// Returns whether `token` is structurally a JWT: three non-empty base64url
+/// segments, the first of which is a JSON header with an `alg`.
+pub(crate) fn is_well_formed(token: &str) -> bool {
+    let segments = token.split('.').collect::<Vec<_>>();
+    let [header, _, _] = segments[..] else {
+        return false;
+    };
+
+    if !segments.iter().all(|segment| {
+        !segment.is_empty()
+            && segment
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    }) {
+        return false;
+    }
+
+    base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(header)
+        .ok()
+        .and_then(|header| serde_json::from_slice::<Map<String, Value>>(&header).ok())
+        .is_some_and(|header| header.get("alg").is_some_and(Value::is_string))
+}
+
+/// Returns the audiences in a JWT's `aud` claim, which may be either
+/// a single string or an array of strings.
+pub(crate) fn audiences(claims: &Map<String, Value>) -> Vec<&str> {
+    match claims.get("aud") {
+        Some(Value::String(aud)) => vec![aud.as_str()],
+        Some(Value::Array(auds)) => auds.iter().filter_map(Value::as_str).collect(),
+        _ => vec![],
+    }
+}
+
+/// Returns whether `token` is a JWT for the given audience.
+pub(crate) fn has_audience(token: &str, audience: &str) -> bool {
+    decode_claims(token).is_some_and(|claims| audiences(&claims).contains(&audience))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use base64::Engine as _;
+    use serde_json::json;
+
+    use super::{audiences, decode_claims, has_audience, is_well_formed};
+
+    /// Builds an unsigned JWT with the given claims.
+    pub(crate) fn fake_jwt(claims: serde_json::Value) -> String {
+        let engine = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        format!(
+            "{header}.{payload}.{signature}",
+            header = engine.encode(json!({"alg": "RS256", "typ": "JWT"}).to_string()),
+            payload = engine.encode(claims.to_string()),
+            signature = engine.encode("not-a-signature"),
+        )
+    }
+
+    #[test]
+    fn test_decode_claims() {
+        let token = fake_jwt(json!({"aud": "bupkis", "sub": "someone"}));
+        let claims = decode_claims(&token).expect("should decode");
+        assert_eq!(claims["sub"], "someone");
+
+        for bogus in ["", "sometoken", "a.b", "a.b.c.d", "eyJ.!!!.sig"] {
+            assert!(decode_claims(bogus).is_none(), "{bogus:?}");
+        }
+    }
+
+    #[test]
+    fn test_is_well_formed() {
+        assert!(is_well_formed(&fake_jwt(json!({"aud": "bupkis"}))));
+
+        let engine = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        let no_alg = format!(
+            "{}.{}.sig",
+            engine.encode(json!({"typ": "JWT"}).to_string()),
+            engine.encode("{}")
+        );
+        let not_json = format!("{}.{}.sig", engine.encode("header"), engine.encode("{}"));
+
+        for bogus in [
+            "",
+            "sometoken",
+            "a.b",
+            "a.b.c.d",
+            "a..c",
+            "eyJ.e30.!!!",
+            "A new version of buildkite-agent is available",
+            no_alg.as_str(),
+            not_json.as_str(),
+        ] {
+            assert!(!is_well_formed(bogus), "{bogus:?}");
+        }
+    }
+
+    #[test]
+    fn test_audiences() {
+        let single = decode_claims(&fake_jwt(json!({"aud": "one"}))).unwrap();
+        assert_eq!(audiences(&single), ["one"]);
+
+        let multiple = decode_claims(&fake_jwt(json!({"aud": ["one", "two"]}))).unwrap();
+        assert_eq!(audiences(&multiple), ["one", "two"]);
+
+        let none = decode_claims(&fake_jwt(json!({}))).unwrap();
+        assert!(audiences(&none).is_empty());
+    }
+
+    #[test]
+    fn test_has_audience() {
+        let token = fake_jwt(json!({"aud": ["one", "two"]}));
+        assert!(has_audience(&token, "two"));
+        assert!(!has_audience(&token, "three"));
+        assert!(!has_audience("sometoken", "two"));
+    }
+}
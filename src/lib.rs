@@ -16,23 +16,55 @@
 //!     None => println!("No ambient ID token detected"),
 //! }
 //! ```
+//!
+//! To request more than an audience, use a [`TokenRequest`]:
+//!
+//! ```rust,ignore
+//! let request = ambient_id::TokenRequest::new("my-service")
+//!     .with_lifetime(std::time::Duration::from_secs(300));
+//! let token = detector.request(&request).await?;
+//! ```
 
 #![deny(rustdoc::broken_intra_doc_links)]
 #![deny(missing_docs)]
 #![deny(unsafe_code)]
 
+use std::{sync::Arc, time::Duration};
+
 use reqwest_middleware::ClientWithMiddleware;
 use secrecy::{ExposeSecret, SecretString};
 
 mod buildkite;
+mod cache;
 mod circleci;
+mod command;
+mod context;
 mod gcp;
 mod github;
 mod gitlab;
+mod http;
+mod identity;
+mod jwt;
+mod policy;
+mod redact;
+mod request;
 
-pub use buildkite::Error as BuildkiteError;
-pub use github::Error as GitHubError;
-pub use gitlab::Error as GitLabError;
+pub use buildkite::{Error as BuildkiteError, Options as BuildkiteOptions};
+pub use circleci::{Issuer as CircleCIIssuer, Options as CircleCIOptions};
+pub use command::Error as CommandError;
+pub use context::BuildContext;
+pub use gcp::{Options as GcpOptions, TokenFormat as GcpTokenFormat};
+pub use github::{
+    Error as GitHubError, Options as GitHubOptions, PermissionReason as GitHubPermissionReason,
+};
+pub use gitlab::{
+    AmbiguityReason as GitLabAmbiguityReason, Error as GitLabError,
+    IdTokenVariable as GitLabIdTokenVariable, Options as GitLabOptions,
+};
+pub use http::Error as HttpError;
+pub use identity::WorkloadIdentity;
+pub use policy::{Error as PolicyError, Policy, Violation as PolicyViolation};
+pub use request::{Feature, Provider, TokenRequest};
 
 /// A detected ID token.
 ///
@@ -50,8 +82,22 @@ impl IdToken {
     pub fn reveal(&self) -> &str {
         self.0.expose_secret()
     }
+
+    /// Returns the workload this token was issued to, normalized across
+    /// providers, or `None` if the token isn't a JWT.
+    ///
+    /// This doesn't verify the token's signature.
+    pub fn identity(&self) -> Option<WorkloadIdentity> {
+        WorkloadIdentity::from_token(self)
+    }
 }
 
+/// Additional claims to request in an ID token, beyond its audience.
+///
+/// Not every environment supports custom claims; see
+/// [`Provider::supports`].
+pub type Claims = serde_json::Map<String, serde_json::Value>;
+
 /// Errors that can occur during detection.
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -70,22 +116,111 @@ pub enum Error {
     /// An error occurred while detecting CircleCI credentials.
     #[error("CircleCI detection error")]
     CircleCI(#[from] circleci::Error),
+    /// The detected environment can't honor part of the token request.
+    #[error("{provider} does not support {feature} ({})", supported_by_hint(.supported_by))]
+    Unsupported {
+        /// The detected environment.
+        provider: Provider,
+        /// The unsupported part of the request.
+        feature: Feature,
+        /// The environments that do support the feature.
+        supported_by: Vec<Provider>,
+    },
+    /// The detected token's claims violate the detector's [`Policy`].
+    #[error(
+        "{provider} token violates the claims policy: {}",
+        violations_hint(.violations)
+    )]
+    PolicyViolation {
+        /// The environment the token was detected in.
+        provider: Provider,
+        /// Every rule the token violated.
+        violations: Vec<PolicyViolation>,
+    },
+}
+
+fn violations_hint(violations: &[PolicyViolation]) -> String {
+    violations
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+fn supported_by_hint(providers: &[Provider]) -> String {
+    if providers.is_empty() {
+        "not supported by any environment".into()
+    } else {
+        let providers = providers
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!("supported by: {providers}")
+    }
 }
 
-#[derive(Default)]
 struct DetectionState {
     client: ClientWithMiddleware,
+    runner: Arc<dyn command::CommandRunner>,
+    command_timeout: Duration,
+    gcp: GcpOptions,
+    github: GitHubOptions,
+    gitlab: GitLabOptions,
+    buildkite: BuildkiteOptions,
+    circleci: CircleCIOptions,
+    policy: Option<Policy>,
+    cache: Option<cache::Cache>,
+    /// Whether the GCP metadata server was found by probing, once probed.
+    gcp_metadata_server: tokio::sync::OnceCell<bool>,
+}
+
+impl Default for DetectionState {
+    fn default() -> Self {
+        DetectionState {
+            client: Default::default(),
+            runner: Arc::new(command::SystemRunner),
+            command_timeout: command::DEFAULT_TIMEOUT,
+            gcp: Default::default(),
+            github: Default::default(),
+            gitlab: Default::default(),
+            buildkite: Default::default(),
+            circleci: Default::default(),
+            policy: None,
+            cache: None,
+            gcp_metadata_server: Default::default(),
+        }
+    }
+}
+
+/// Returns the environment's variables, skipping any whose name or value
+/// isn't valid UTF-8 (unlike [`std::env::vars`], which panics on them).
+fn utf8_env_vars() -> impl Iterator<Item = (String, String)> {
+    std::env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
 }
 
 /// A trait for detecting ambient OIDC credentials.
 trait DetectionStrategy {
     type Error;
 
-    fn new(state: &DetectionState) -> Option<Self>
+    /// The environment this strategy detects.
+    const PROVIDER: Provider;
+
+    async fn new(state: &DetectionState) -> Option<Self>
     where
         Self: Sized;
 
     async fn detect(&self, audience: &str) -> Result<IdToken, Self::Error>;
+
+    /// Detects a token for the given request.
+    ///
+    /// Only strategies whose [`PROVIDER`](Self::PROVIDER) supports
+    /// some [`Feature`] need to implement this; others are only ever called
+    /// with requests that use no features.
+    async fn request(&self, request: &TokenRequest) -> Result<IdToken, Self::Error> {
+        self.detect(request.audience()).await
+    }
 }
 
 /// Detector for ambient OIDC credentials.
@@ -112,10 +247,81 @@ impl Detector {
         Detector {
             state: DetectionState {
                 client: client.into(),
+                ..Default::default()
             },
         }
     }
 
+    /// Sets the time limit for CLI tools (e.g. `buildkite-agent`) to
+    /// produce a token.
+    ///
+    /// Tools that exceed this limit are killed, and detection fails with
+    /// [`CommandError::TimedOut`]. The default is 30 seconds.
+    pub fn with_command_timeout(mut self, timeout: Duration) -> Self {
+        self.state.command_timeout = timeout;
+        self
+    }
+
+    /// Sets the options used when requesting ID tokens on Buildkite.
+    pub fn with_buildkite_options(mut self, options: BuildkiteOptions) -> Self {
+        self.state.buildkite = options;
+        self
+    }
+
+    /// Sets the options used when requesting ID tokens on CircleCI.
+    pub fn with_circleci_options(mut self, options: CircleCIOptions) -> Self {
+        self.state.circleci = options;
+        self
+    }
+
+    /// Sets the options used when obtaining ID tokens on Google Cloud Platform.
+    pub fn with_gcp_options(mut self, options: GcpOptions) -> Self {
+        self.state.gcp = options;
+        self
+    }
+
+    /// Sets the options used when requesting ID tokens on GitHub Actions.
+    pub fn with_github_options(mut self, options: GitHubOptions) -> Self {
+        self.state.github = options;
+        self
+    }
+
+    /// Sets the options used when looking up ID tokens on GitLab CI.
+    pub fn with_gitlab_options(mut self, options: GitLabOptions) -> Self {
+        self.state.gitlab = options;
+        self
+    }
+
+    /// Sets a policy that detected tokens' claims must satisfy.
+    ///
+    /// Tokens that violate it aren't returned; instead, detection fails
+    /// with [`Error::PolicyViolation`]. Tokens that aren't JWTs are treated
+    /// as having no claims.
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.state.policy = Some(policy);
+        self
+    }
+
+    /// Caches detected tokens, reusing each until `margin` before it expires.
+    ///
+    /// Tokens are cached per environment and request (audience, lifetime
+    /// and claims), and only if they have an `exp` claim. Concurrent requests
+    /// for the same token wait for a single request to the environment.
+    /// The cache lives as long as the detector.
+    pub fn with_cache(mut self, margin: Duration) -> Self {
+        self.state.cache = Some(cache::Cache::new(margin));
+        self
+    }
+
+    /// Returns the context of the CI build we're running in, if any.
+    ///
+    /// This uses the same detection as [`detect`](Self::detect), but only
+    /// reads environment variables: it never requests a token or makes
+    /// network calls. GCP isn't a CI provider, so it's never reported here.
+    pub fn environment(&self) -> Option<BuildContext> {
+        context::detect()
+    }
+
     /// Detects ambient OIDC credentials in the current environment.
     ///
     /// The given `audience` controls the `aud` claim in the returned ID token.
@@ -126,17 +332,89 @@ impl Detector {
     ///
     /// If any (hard) errors occur during detection, it returns `Err`.
     pub async fn detect(&self, audience: &str) -> Result<Option<IdToken>, Error> {
+        self.request(&TokenRequest::new(audience)).await
+    }
+
+    /// Detects ambient OIDC credentials in the current environment,
+    /// requesting the given custom claims in addition to the audience.
+    ///
+    /// This is shorthand for [`request`](Self::request) with
+    /// [`TokenRequest::with_claims`].
+    pub async fn detect_with_claims(
+        &self,
+        audience: &str,
+        claims: &Claims,
+    ) -> Result<Option<IdToken>, Error> {
+        self.request(&TokenRequest::new(audience).with_claims(claims.clone()))
+            .await
+    }
+
+    /// Detects ambient OIDC credentials in the current environment,
+    /// honoring every part of the given request.
+    ///
+    /// This behaves like [`detect`](Self::detect), except that if the
+    /// detected environment can't honor part of the request (e.g. a lifetime
+    /// or custom claims), it returns [`Error::Unsupported`] rather than
+    /// silently ignoring it. See [`Provider::supports`] for which
+    /// environments support what.
+    pub async fn request(&self, request: &TokenRequest) -> Result<Option<IdToken>, Error> {
+        async fn run<S>(
+            strategy: S,
+            request: &TokenRequest,
+            state: &DetectionState,
+        ) -> Result<IdToken, Error>
+        where
+            S: DetectionStrategy,
+            Error: From<S::Error>,
+        {
+            if let Some(feature) = request
+                .features()
+                .find(|feature| !S::PROVIDER.supports(*feature))
+            {
+                return Err(Error::Unsupported {
+                    provider: S::PROVIDER,
+                    feature,
+                    supported_by: feature.supported_by(),
+                });
+            }
+
+            let fetch = async || {
+                let token = strategy.request(request).await?;
+
+                if let Some(policy) = &state.policy {
+                    let claims = jwt::decode_claims(token.reveal()).unwrap_or_default();
+                    policy
+                        .check(&claims)
+                        .map_err(|violations| Error::PolicyViolation {
+                            provider: S::PROVIDER,
+                            violations,
+                        })?;
+                }
+
+                Ok(token)
+            };
+
+            match &state.cache {
+                Some(cache) => cache.get_or_fetch(S::PROVIDER, request, fetch).await,
+                None => fetch().await,
+            }
+        }
+
         macro_rules! detect {
         ($detector:path) => {
-            if let Some(detector) = <$detector>::new(&self.state) {
-                detector.detect(audience).await.map_err(Into::into).map(Some)
+            if let Some(detector) = <$detector>::new(&self.state).await {
+                run(detector, request, &self.state)
+                    .await
+                    .map(Some)
             } else {
                 Ok(None)
             }
         };
         ($detector:path, $($rest:path),+) => {
-            if let Some(detector) = <$detector>::new(&self.state) {
-                detector.detect(audience).await.map_err(Into::into).map(Some)
+            if let Some(detector) = <$detector>::new(&self.state).await {
+                run(detector, request, &self.state)
+                    .await
+                    .map(Some)
             } else {
                 detect!($($rest),+)
             }
@@ -148,19 +426,25 @@ impl Detector {
             github::GitHubActions,
             gitlab::GitLabCI,
             buildkite::Buildkite,
-            circleci::CircleCI
+            circleci::CircleCI,
+            gcp::GcpFallback
         )
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::Detector;
+    use std::{
+        ffi::{OsStr, OsString},
+        time::Duration,
+    };
+
+    use crate::{Claims, Detector, Error, Feature, Policy, Provider, TokenRequest, jwt};
 
     /// An environment variable delta.
     enum EnvDelta {
         /// Set an environment variable to a value.
-        Add(String, String),
+        Add(String, OsString),
         /// Unset an environment variable.
         Remove(String),
     }
@@ -179,13 +463,19 @@ mod tests {
         }
 
         /// Sets an environment variable for the duration of this scope.
-        #[allow(unsafe_code)]
         pub fn setenv(&mut self, key: &str, value: &str) {
-            match std::env::var(key) {
+            self.setenv_os(key, OsStr::new(value));
+        }
+
+        /// Sets an environment variable to a value that may not be valid
+        /// UTF-8, for the duration of this scope.
+        #[allow(unsafe_code)]
+        pub fn setenv_os(&mut self, key: &str, value: &OsStr) {
+            match std::env::var_os(key) {
                 // Key was set before; restore old value on drop.
-                Ok(old) => self.changes.push(EnvDelta::Add(key.to_string(), old)),
+                Some(old) => self.changes.push(EnvDelta::Add(key.to_string(), old)),
                 // Key was not set before; remove it on drop.
-                Err(_) => self.changes.push(EnvDelta::Remove(key.to_string())),
+                None => self.changes.push(EnvDelta::Remove(key.to_string())),
             }
 
             unsafe { std::env::set_var(key, value) };
@@ -194,11 +484,10 @@ mod tests {
         /// Removes an environment variable for the duration of this scope.
         #[allow(unsafe_code)]
         pub fn unsetenv(&mut self, key: &str) {
-            match std::env::var(key) {
-                // Key was set before; restore old value on drop.
-                Ok(old) => self.changes.push(EnvDelta::Add(key.to_string(), old)),
-                // Key was not set before; nothing to do.
-                Err(_) => {}
+            // Key was set before; restore old value on drop.
+            // If it wasn't set, there's nothing to do.
+            if let Some(old) = std::env::var_os(key) {
+                self.changes.push(EnvDelta::Add(key.to_string(), old));
             }
 
             unsafe { std::env::remove_var(key) };
@@ -225,6 +514,10 @@ mod tests {
         scope.unsetenv("GITLAB_CI");
         scope.unsetenv("BUILDKITE");
         scope.unsetenv("CIRCLECI");
+        scope.unsetenv("GOOGLE_APPLICATION_CREDENTIALS");
+        scope.unsetenv("GOOGLE_SERVICE_ACCOUNT_NAME");
+        scope.setenv("CLOUDSDK_CONFIG", "/nonexistent");
+        scope.setenv("NO_GCE_CHECK", "true");
 
         let detector = Detector::new();
 
@@ -236,4 +529,178 @@ mod tests {
                 .is_none()
         );
     }
+
+    #[tokio::test]
+    async fn test_ci_provider_before_application_credentials() {
+        let mut scope = EnvScope::new();
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("credentials.json");
+        std::fs::write(&config_path, r#"{"type": "external_account"}"#).unwrap();
+
+        scope.setenv("GITLAB_CI", "true");
+        scope.setenv("BUPKIS_ID_TOKEN", "sometoken");
+        scope.unsetenv("GITHUB_ACTIONS");
+        scope.setenv(
+            "GOOGLE_APPLICATION_CREDENTIALS",
+            config_path.to_str().unwrap(),
+        );
+        scope.unsetenv("GOOGLE_SERVICE_ACCOUNT_NAME");
+        scope.setenv("NO_GCE_CHECK", "true");
+
+        let token = Detector::new()
+            .detect("bupkis")
+            .await
+            .expect("should not error")
+            .expect("should detect GitLab CI");
+        assert_eq!(token.reveal(), "sometoken");
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_custom_claims() {
+        let mut scope = EnvScope::new();
+        scope.setenv("GITLAB_CI", "true");
+        scope.setenv("BUPKIS_ID_TOKEN", "sometoken");
+        scope.unsetenv("GITHUB_ACTIONS");
+        scope.unsetenv("GOOGLE_APPLICATION_CREDENTIALS");
+        scope.unsetenv("GOOGLE_SERVICE_ACCOUNT_NAME");
+        scope.setenv("CLOUDSDK_CONFIG", "/nonexistent");
+        scope.setenv("NO_GCE_CHECK", "true");
+
+        let detector = Detector::new();
+
+        // No claims: the token is returned as usual.
+        assert!(
+            detector
+                .detect_with_claims("bupkis", &Claims::new())
+                .await
+                .expect("should not error")
+                .is_some()
+        );
+
+        // GitLab can't issue tokens with custom claims.
+        let mut claims = Claims::new();
+        claims.insert("env".into(), "prod".into());
+        match detector.detect_with_claims("bupkis", &claims).await {
+            Err(Error::Unsupported {
+                provider, feature, ..
+            }) => {
+                assert_eq!(provider, Provider::GitLabCI);
+                assert_eq!(feature, Feature::CustomClaims);
+            }
+            _ => panic!("expected unsupported error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_lifetime() {
+        let mut scope = EnvScope::new();
+        scope.setenv("GITLAB_CI", "true");
+        scope.setenv("BUPKIS_ID_TOKEN", "sometoken");
+        scope.unsetenv("GITHUB_ACTIONS");
+        scope.unsetenv("GOOGLE_APPLICATION_CREDENTIALS");
+        scope.unsetenv("GOOGLE_SERVICE_ACCOUNT_NAME");
+        scope.setenv("CLOUDSDK_CONFIG", "/nonexistent");
+        scope.setenv("NO_GCE_CHECK", "true");
+
+        let detector = Detector::new();
+        let request = TokenRequest::new("bupkis").with_lifetime(Duration::from_secs(300));
+
+        let err = detector
+            .request(&request)
+            .await
+            .err()
+            .expect("should be unsupported");
+        assert!(matches!(
+            &err,
+            Error::Unsupported {
+                provider: Provider::GitLabCI,
+                feature: Feature::Lifetime,
+                supported_by,
+            } if supported_by == &[Provider::Buildkite]
+        ));
+        assert_eq!(
+            err.to_string(),
+            "GitLab CI does not support token lifetimes (supported by: Buildkite)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        use wiremock::{Mock, MockServer, ResponseTemplate, matchers::method};
+
+        let mut scope = EnvScope::new();
+        let server = MockServer::start().await;
+        scope.setenv("GITHUB_ACTIONS", "true");
+        scope.setenv("ACTIONS_ID_TOKEN_REQUEST_URL", &server.uri());
+        scope.setenv("ACTIONS_ID_TOKEN_REQUEST_TOKEN", "bogus");
+        scope.unsetenv("GOOGLE_APPLICATION_CREDENTIALS");
+        scope.unsetenv("GOOGLE_SERVICE_ACCOUNT_NAME");
+        scope.setenv("CLOUDSDK_CONFIG", "/nonexistent");
+        scope.setenv("NO_GCE_CHECK", "true");
+
+        let exp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 600;
+        let token = jwt::tests::fake_jwt(serde_json::json!({ "exp": exp }));
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({ "value": token })),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let detector = Detector::new().with_cache(Duration::from_secs(60));
+        let (a, b) = tokio::join!(detector.detect("bupkis"), detector.detect("bupkis"));
+        let c = detector.detect("bupkis").await;
+        for detected in [a, b, c] {
+            assert_eq!(detected.unwrap().unwrap().reveal(), token);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_policy_violation() {
+        let mut scope = EnvScope::new();
+        scope.setenv("GITLAB_CI", "true");
+        scope.setenv(
+            "BUPKIS_ID_TOKEN",
+            &jwt::tests::fake_jwt(serde_json::json!({
+                "iss": "https://gitlab.com",
+                "project_path": "astral-sh/x",
+                "ref": "dev",
+            })),
+        );
+        scope.unsetenv("GITHUB_ACTIONS");
+        scope.unsetenv("GOOGLE_APPLICATION_CREDENTIALS");
+        scope.unsetenv("GOOGLE_SERVICE_ACCOUNT_NAME");
+        scope.setenv("CLOUDSDK_CONFIG", "/nonexistent");
+        scope.setenv("NO_GCE_CHECK", "true");
+
+        let policy = Policy::new()
+            .with_issuer("https://gitlab.com")
+            .with_claim_equals("project_path", "astral-sh/x");
+
+        let detector = Detector::new().with_policy(policy.clone());
+        assert!(detector.detect("bupkis").await.unwrap().is_some());
+
+        let detector = Detector::new().with_policy(policy.with_claim_equals("ref", "main"));
+        let err = detector
+            .detect("bupkis")
+            .await
+            .err()
+            .expect("should violate the policy");
+        assert!(matches!(
+            &err,
+            Error::PolicyViolation {
+                provider: Provider::GitLabCI,
+                violations,
+            } if violations.len() == 1
+        ));
+        assert_eq!(
+            err.to_string(),
+            "GitLab CI token violates the claims policy: `ref` is `dev`, but must equal `main`"
+        );
+    }
 }
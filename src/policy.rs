@@ -0,0 +1,502 @@
+//! Claims policies that detected tokens must satisfy.
+
+use std::fmt;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::Claims;
+
+/// Possible errors when building or loading a [`Policy`].
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// A rule's regular expression is invalid.
+    #[error("invalid regular expression for `{claim}`")]
+    Regex {
+        /// The claim the rule applies to.
+        claim: String,
+        /// The underlying error.
+        #[source]
+        source: regex::Error,
+    },
+    /// The policy couldn't be parsed as JSON.
+    #[error("invalid JSON policy")]
+    Json(#[from] serde_json::Error),
+    /// The policy couldn't be parsed as TOML.
+    #[cfg(feature = "toml")]
+    #[error("invalid TOML policy")]
+    Toml(#[from] toml::de::Error),
+}
+
+/// Requirements that a detected token's claims must satisfy before
+/// the token is returned.
+///
+/// A policy has an optional allowlist of issuers and a list of claim rules.
+/// Every rule must pass; a policy with neither issuers nor rules allows
+/// any token. Policies can be built in code:
+///
+/// ```rust
+/// # use ambient_id::Policy;
+/// let policy = Policy::new()
+///     .with_issuer("https://token.actions.githubusercontent.com")
+///     .with_claim_equals("repository", "astral-sh/x")
+///     .with_claim_glob("ref", "refs/tags/v*");
+/// ```
+///
+/// or loaded from JSON (or, with the `toml` feature, TOML):
+///
+/// ```toml
+/// issuers = ["https://token.actions.githubusercontent.com"]
+///
+/// [[claims]]
+/// claim = "repository"
+/// equals = "astral-sh/x"
+///
+/// [[claims]]
+/// claim = "ref"
+/// regex = "refs/(heads/main|tags/v.*)"
+/// ```
+///
+/// Claims that are arrays (e.g. `aud`) pass a rule if any of their elements
+/// do. Numbers and booleans are compared by their JSON representation.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default)]
+    issuers: Vec<String>,
+    #[serde(default)]
+    claims: Vec<Rule>,
+}
+
+/// A requirement on a single claim.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    claim: String,
+    #[serde(flatten)]
+    matcher: Matcher,
+}
+
+/// How a claim's value must match.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Matcher {
+    /// The value must equal the string exactly.
+    Equals(String),
+    /// The value must match the glob, where `*` matches any run of
+    /// characters (including `/`) and `?` matches any single character.
+    Glob(String),
+    /// The value must match the regular expression in its entirety.
+    Regex(#[serde(deserialize_with = "deserialize_regex")] Pattern),
+}
+
+/// A regular expression, along with its source.
+#[derive(Clone, Debug)]
+struct Pattern {
+    source: String,
+    regex: regex::Regex,
+}
+
+impl Pattern {
+    fn new(source: String) -> Result<Self, regex::Error> {
+        // Anchor the pattern so that it must match the whole value; an
+        // unanchored `astral-sh/x` would also allow `evil/astral-sh/x`.
+        let regex = regex::Regex::new(&format!("^(?:{source})$"))?;
+        Ok(Pattern { source, regex })
+    }
+}
+
+fn deserialize_regex<'de, D>(deserializer: D) -> Result<Pattern, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Pattern::new(String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+impl Matcher {
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            Matcher::Equals(expected) => value == expected,
+            Matcher::Glob(glob) => glob_matches(glob, value),
+            Matcher::Regex(pattern) => pattern.regex.is_match(value),
+        }
+    }
+}
+
+impl fmt::Display for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Matcher::Equals(expected) => write!(f, "equal `{expected}`"),
+            Matcher::Glob(glob) => write!(f, "match the glob `{glob}`"),
+            Matcher::Regex(pattern) => write!(f, "match the regex `{}`", pattern.source),
+        }
+    }
+}
+
+/// Returns whether `value` matches `glob` in its entirety.
+fn glob_matches(glob: &str, value: &str) -> bool {
+    let glob = glob.chars().collect::<Vec<_>>();
+    let value = value.chars().collect::<Vec<_>>();
+
+    // The classic backtracking matcher: on a mismatch, retry from just
+    // after the most recent `*`, with it consuming one more character.
+    let (mut g, mut v) = (0, 0);
+    let mut backtrack = None;
+    while v < value.len() {
+        match glob.get(g) {
+            Some('*') => {
+                backtrack = Some((g, v));
+                g += 1;
+            }
+            Some('?') => {
+                g += 1;
+                v += 1;
+            }
+            Some(c) if *c == value[v] => {
+                g += 1;
+                v += 1;
+            }
+            _ => match backtrack {
+                Some((star, consumed)) => {
+                    backtrack = Some((star, consumed + 1));
+                    g = star + 1;
+                    v = consumed + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    glob[g..].iter().all(|c| *c == '*')
+}
+
+/// A failed [`Policy`] rule.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    /// The claim the rule applies to.
+    pub claim: String,
+    /// What the rule requires, e.g. "equal `refs/heads/main`".
+    pub expected: String,
+    /// The claim's actual value, or `None` if the token lacks it.
+    pub actual: Option<String>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.actual {
+            Some(actual) => write!(
+                f,
+                "`{}` is `{actual}`, but must {}",
+                self.claim, self.expected
+            ),
+            None => write!(f, "`{}` is missing, but must {}", self.claim, self.expected),
+        }
+    }
+}
+
+impl Policy {
+    /// Creates an empty policy, which allows any token.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads a policy from JSON.
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// Loads a policy from TOML.
+    #[cfg(feature = "toml")]
+    pub fn from_toml(toml: &str) -> Result<Self, Error> {
+        Ok(toml::from_str(toml)?)
+    }
+
+    /// Allows tokens from the given issuer (`iss`).
+    ///
+    /// If no issuers are allowed explicitly, tokens from any issuer are.
+    pub fn with_issuer(mut self, issuer: impl Into<String>) -> Self {
+        self.issuers.push(issuer.into());
+        self
+    }
+
+    /// Requires the given claim to equal `value`.
+    pub fn with_claim_equals(mut self, claim: impl Into<String>, value: impl Into<String>) -> Self {
+        self.claims.push(Rule {
+            claim: claim.into(),
+            matcher: Matcher::Equals(value.into()),
+        });
+        self
+    }
+
+    /// Requires the given claim to match `glob`, in which `*` matches any
+    /// run of characters (including `/`) and `?` matches any single character.
+    pub fn with_claim_glob(mut self, claim: impl Into<String>, glob: impl Into<String>) -> Self {
+        self.claims.push(Rule {
+            claim: claim.into(),
+            matcher: Matcher::Glob(glob.into()),
+        });
+        self
+    }
+
+    /// Requires the given claim to match the regular expression `pattern`
+    /// in its entirety.
+    pub fn with_claim_regex(
+        mut self,
+        claim: impl Into<String>,
+        pattern: impl Into<String>,
+    ) -> Result<Self, Error> {
+        let claim = claim.into();
+        let pattern = Pattern::new(pattern.into()).map_err(|source| Error::Regex {
+            claim: claim.clone(),
+            source,
+        })?;
+
+        self.claims.push(Rule {
+            claim,
+            matcher: Matcher::Regex(pattern),
+        });
+        Ok(self)
+    }
+
+    /// Checks the given claims against this policy, returning every
+    /// violated rule.
+    pub fn check(&self, claims: &Claims) -> Result<(), Vec<Violation>> {
+        let mut violations = vec![];
+
+        if !self.issuers.is_empty() {
+            let issuer = claims.get("iss").and_then(Value::as_str);
+            if !issuer.is_some_and(|issuer| self.issuers.iter().any(|allowed| allowed == issuer)) {
+                let allowed = self
+                    .issuers
+                    .iter()
+                    .map(|issuer| format!("`{issuer}`"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                violations.push(Violation {
+                    claim: "iss".into(),
+                    expected: format!("be one of {allowed}"),
+                    actual: issuer.map(str::to_string),
+                });
+            }
+        }
+
+        for rule in &self.claims {
+            let values = claims.get(&rule.claim).map(values).unwrap_or_default();
+            if !values.iter().any(|value| rule.matcher.matches(value)) {
+                violations.push(Violation {
+                    claim: rule.claim.clone(),
+                    expected: rule.matcher.to_string(),
+                    actual: claims.get(&rule.claim).map(|value| match value {
+                        Value::String(value) => value.clone(),
+                        value => value.to_string(),
+                    }),
+                });
+            }
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+}
+
+/// Returns the values a claim's rules are matched against.
+fn values(value: &Value) -> Vec<String> {
+    match value {
+        Value::String(value) => vec![value.clone()],
+        Value::Array(values) => values.iter().flat_map(self::values).collect(),
+        Value::Null | Value::Object(_) => vec![],
+        value => vec![value.to_string()],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::Claims;
+
+    use super::{Policy, Violation, glob_matches};
+
+    fn claims(claims: serde_json::Value) -> Claims {
+        let serde_json::Value::Object(claims) = claims else {
+            panic!("claims must be an object");
+        };
+        claims
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("refs/tags/v*", "refs/tags/v1.0.0"));
+        assert!(glob_matches("refs/*/main", "refs/heads/main"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("v?.?", "v1.2"));
+        assert!(glob_matches("a*b*c", "aXXbYYbc"));
+        assert!(!glob_matches("refs/tags/v*", "refs/heads/v1"));
+        assert!(!glob_matches("v?", "v10"));
+        assert!(!glob_matches("main", "main2"));
+    }
+
+    #[test]
+    fn test_empty_policy() {
+        assert_eq!(Policy::new().check(&claims(json!({}))), Ok(()));
+    }
+
+    #[test]
+    fn test_check() {
+        let policy = Policy::new()
+            .with_issuer("https://token.actions.githubusercontent.com")
+            .with_claim_equals("repository", "astral-sh/x")
+            .with_claim_glob("ref", "refs/heads/*")
+            .with_claim_regex(
+                "workflow_ref",
+                r"astral-sh/x/\.github/workflows/release\.yml@.*",
+            )
+            .unwrap()
+            .with_claim_equals("aud", "pypi")
+            .with_claim_equals("run_attempt", "1");
+
+        let ok = claims(json!({
+            "iss": "https://token.actions.githubusercontent.com",
+            "repository": "astral-sh/x",
+            "ref": "refs/heads/main",
+            "workflow_ref": "astral-sh/x/.github/workflows/release.yml@refs/heads/main",
+            "aud": ["pypi", "sigstore"],
+            "run_attempt": 1,
+        }));
+        assert_eq!(policy.check(&ok), Ok(()));
+
+        let bad = claims(json!({
+            "iss": "https://gitlab.com",
+            "repository": "evil/astral-sh/x",
+            "ref": "refs/tags/v1",
+            // Regexes must match in their entirety.
+            "workflow_ref": "evil/astral-sh/x/.github/workflows/release.yml@refs/heads/main",
+            "aud": "pypi",
+        }));
+        assert_eq!(
+            policy.check(&bad),
+            Err(vec![
+                Violation {
+                    claim: "iss".into(),
+                    expected: "be one of `https://token.actions.githubusercontent.com`".into(),
+                    actual: Some("https://gitlab.com".into()),
+                },
+                Violation {
+                    claim: "repository".into(),
+                    expected: "equal `astral-sh/x`".into(),
+                    actual: Some("evil/astral-sh/x".into()),
+                },
+                Violation {
+                    claim: "ref".into(),
+                    expected: "match the glob `refs/heads/*`".into(),
+                    actual: Some("refs/tags/v1".into()),
+                },
+                Violation {
+                    claim: "workflow_ref".into(),
+                    expected: r"match the regex `astral-sh/x/\.github/workflows/release\.yml@.*`"
+                        .into(),
+                    actual: Some(
+                        "evil/astral-sh/x/.github/workflows/release.yml@refs/heads/main".into()
+                    ),
+                },
+                Violation {
+                    claim: "run_attempt".into(),
+                    expected: "equal `1`".into(),
+                    actual: None,
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_violation_display() {
+        let violation = Violation {
+            claim: "ref".into(),
+            expected: "equal `refs/heads/main`".into(),
+            actual: Some("refs/heads/dev".into()),
+        };
+        assert_eq!(
+            violation.to_string(),
+            "`ref` is `refs/heads/dev`, but must equal `refs/heads/main`"
+        );
+
+        let violation = Violation {
+            actual: None,
+            ..violation
+        };
+        assert_eq!(
+            violation.to_string(),
+            "`ref` is missing, but must equal `refs/heads/main`"
+        );
+    }
+
+    #[test]
+    fn test_invalid_regex() {
+        assert!(matches!(
+            Policy::new().with_claim_regex("ref", "refs/(heads"),
+            Err(super::Error::Regex { claim, .. }) if claim == "ref"
+        ));
+    }
+
+    #[test]
+    fn test_from_json() {
+        let policy = Policy::from_json(
+            r#"{
+                "issuers": ["https://token.actions.githubusercontent.com"],
+                "claims": [
+                    { "claim": "repository", "equals": "astral-sh/x" },
+                    { "claim": "ref", "regex": "refs/(heads/main|tags/v.*)" }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let mut token = claims(json!({
+            "iss": "https://token.actions.githubusercontent.com",
+            "repository": "astral-sh/x",
+            "ref": "refs/tags/v1.0.0",
+        }));
+        assert_eq!(policy.check(&token), Ok(()));
+
+        token["ref"] = json!("refs/heads/dev");
+        assert_eq!(policy.check(&token).unwrap_err().len(), 1);
+
+        assert!(matches!(
+            Policy::from_json(r#"{ "claims": [{ "claim": "ref", "regex": "(" }] }"#),
+            Err(super::Error::Json(_))
+        ));
+        assert!(matches!(
+            Policy::from_json(r#"{ "claims": [{ "claim": "ref", "prefix": "refs/" }] }"#),
+            Err(super::Error::Json(_))
+        ));
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn test_from_toml() {
+        let policy = Policy::from_toml(
+            r#"
+            issuers = ["https://token.actions.githubusercontent.com"]
+
+            [[claims]]
+            claim = "repository"
+            equals = "astral-sh/x"
+
+            [[claims]]
+            claim = "ref"
+            glob = "refs/tags/v*"
+            "#,
+        )
+        .unwrap();
+
+        let token = claims(json!({
+            "iss": "https://token.actions.githubusercontent.com",
+            "repository": "astral-sh/x",
+            "ref": "refs/tags/v1.0.0",
+        }));
+        assert_eq!(policy.check(&token), Ok(()));
+    }
+}
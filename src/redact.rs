@@ -0,0 +1,230 @@
+//! Redaction of potentially secret text before it's shown to users.
+
+/// The placeholder for redacted text.
+const REDACTED: &str = "[REDACTED]";
+
+/// Runs of token-like characters at least this long are redacted
+/// in previews, since they may be (partial) credentials.
+const PREVIEW_SECRET_LEN: usize = 16;
+
+/// The maximum length of a preview, in characters.
+const PREVIEW_MAX_LEN: usize = 80;
+
+/// Words that precede a credential, as in an `Authorization` header.
+const AUTH_SCHEMES: &[&str] = &["Bearer", "bearer", "Token", "token"];
+
+/// Credentials following an [`AUTH_SCHEMES`] word are at least this long;
+/// shorter words are assumed to be prose (e.g. "token from").
+const AUTH_CREDENTIAL_MIN_LEN: usize = 8;
+
+/// Environment variables with these suffixes are assumed to hold secrets.
+///
+/// `KEY` also covers e.g. `AWS_SECRET_ACCESS_KEY` and `*_API_KEY`.
+const SECRET_ENV_SUFFIXES: &[&str] = &[
+    "TOKEN",
+    "TOKEN_V2",
+    "SECRET",
+    "PASSWORD",
+    "KEY",
+    "CREDENTIALS",
+];
+
+/// Secret environment variable values shorter than this aren't redacted,
+/// since they're unlikely to be secrets (e.g. `true`) and would mangle
+/// unrelated text.
+const SECRET_ENV_MIN_LEN: usize = 8;
+
+/// Redacts potential secrets from `text`, which is about to be shown
+/// to users (e.g. in an error message).
+///
+/// This masks:
+///
+/// * the values of environment variables that look like they hold secrets,
+///   e.g. `BUILDKITE_AGENT_ACCESS_TOKEN` or GitLab's `<AUD>_ID_TOKEN`s;
+/// * JWT-shaped strings, including partial ones;
+/// * credentials following `Bearer` or `Token`.
+pub(crate) fn redact(text: &str) -> String {
+    let mut text = text.to_string();
+
+    let mut secrets = crate::utf8_env_vars()
+        .filter(|(name, value)| {
+            SECRET_ENV_SUFFIXES
+                .iter()
+                .any(|suffix| name.to_ascii_uppercase().ends_with(suffix))
+                && value.len() >= SECRET_ENV_MIN_LEN
+        })
+        .map(|(_, value)| value)
+        .collect::<Vec<_>>();
+    // Longest first, so that a secret containing another is fully masked.
+    secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+    for secret in secrets {
+        text = text.replace(&secret, REDACTED);
+    }
+
+    let mut redacted = String::with_capacity(text.len());
+    let mut previous_word = "";
+    let mut rest = text.as_str();
+    while let Some(start) = rest.find(is_token_char) {
+        let (before, word) = rest.split_at(start);
+        let end = word.find(|c| !is_token_char(c)).unwrap_or(word.len());
+        let (word, after) = word.split_at(end);
+
+        // Only a single space may separate a scheme from its credential.
+        let follows_scheme = before == " " && AUTH_SCHEMES.contains(&previous_word);
+        let is_jwt = word.starts_with("eyJ") && word.contains('.');
+
+        redacted.push_str(before);
+        if is_jwt || (follows_scheme && word.len() >= AUTH_CREDENTIAL_MIN_LEN) {
+            redacted.push_str(REDACTED);
+        } else {
+            redacted.push_str(word);
+        }
+
+        previous_word = word;
+        rest = after;
+    }
+    redacted.push_str(rest);
+
+    redacted
+}
+
+/// Returns a short, single-line preview of `text` that's safe to include
+/// in an error message.
+///
+/// In addition to [`redact`]ing `text`, long runs of token-like characters
+/// (e.g. base64 or JWT segments) are redacted, whitespace is collapsed,
+/// and the result is truncated.
+pub(crate) fn preview(text: &str) -> String {
+    let text = redact(text)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    if text.is_empty() {
+        return "<empty>".into();
+    }
+
+    let mut redacted = String::with_capacity(text.len());
+    let mut run = String::new();
+    for c in text.chars() {
+        if is_token_char(c) {
+            run.push(c);
+        } else {
+            flush_run(&mut redacted, &mut run);
+            redacted.push(c);
+        }
+    }
+    flush_run(&mut redacted, &mut run);
+
+    if redacted.chars().count() > PREVIEW_MAX_LEN {
+        let truncated = redacted.chars().take(PREVIEW_MAX_LEN).collect::<String>();
+        format!("{truncated}…")
+    } else {
+        redacted
+    }
+}
+
+fn is_token_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '+' | '/' | '=')
+}
+
+fn flush_run(out: &mut String, run: &mut String) {
+    if run.len() >= PREVIEW_SECRET_LEN {
+        out.push_str(REDACTED);
+    } else {
+        out.push_str(run);
+    }
+    run.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::EnvScope;
+
+    use super::{preview, redact};
+
+    #[test]
+    fn test_redact_jwt() {
+        assert_eq!(
+            redact("bad token 'eyJhbGciOiJSUzI1NiJ9.eyJhdWQiOiJidXBraXMifQ.c2ln' rejected"),
+            "bad token '[REDACTED]' rejected"
+        );
+        // Partial tokens are redacted too.
+        assert_eq!(redact("got eyJhbGciOiJSUzI1NiJ9.eyJhdWQ"), "got [REDACTED]");
+        // Lookalikes without a segment separator aren't.
+        assert_eq!(redact("eyJust kidding"), "eyJust kidding");
+    }
+
+    #[test]
+    fn test_redact_auth_schemes() {
+        assert_eq!(
+            redact("Authorization: Bearer abcdef0123456789"),
+            "Authorization: Bearer [REDACTED]"
+        );
+        assert_eq!(
+            redact("Authorization: Token abcdef0123456789"),
+            "Authorization: Token [REDACTED]"
+        );
+        // Prose is left alone.
+        assert_eq!(
+            redact("failed to get token from the agent"),
+            "failed to get token from the agent"
+        );
+    }
+
+    #[test]
+    fn test_redact_secret_env_values() {
+        let mut scope = EnvScope::new();
+        scope.setenv("BUILDKITE_AGENT_ACCESS_TOKEN", "s3cr3t-agent-access");
+        scope.setenv("BUPKIS_ID_TOKEN", "not-a-jwt-but-secret");
+        scope.setenv("SOME_TOKEN", "true");
+        scope.setenv("AWS_SECRET_ACCESS_KEY", "wJalrXUtnFEMI/K7MDENG");
+        scope.setenv("GOOGLE_CREDENTIALS", "{\"type\": \"service_account\"}");
+        scope.setenv("BUPKIS_API_KEY", "sk-bupkis-api");
+
+        assert_eq!(
+            redact("401: s3cr3t-agent-access is invalid (not-a-jwt-but-secret); true"),
+            "401: [REDACTED] is invalid ([REDACTED]); true"
+        );
+        assert_eq!(
+            redact(
+                "wJalrXUtnFEMI/K7MDENG, sk-bupkis-api and {\"type\": \"service_account\"} leaked"
+            ),
+            "[REDACTED], [REDACTED] and [REDACTED] leaked"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_redact_non_utf8_env() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt as _};
+
+        let mut scope = EnvScope::new();
+        scope.setenv_os("BUPKIS_NON_UTF8", OsStr::from_bytes(b"\xff\xfe"));
+        scope.setenv("BUPKIS_\u{e9}_TOKEN", "s3cr3t-but-fine");
+        scope.setenv("BUPKIS_ID_TOKEN", "not-a-jwt-but-secret");
+
+        assert_eq!(
+            redact("not-a-jwt-but-secret and s3cr3t-but-fine"),
+            "[REDACTED] and [REDACTED]"
+        );
+    }
+
+    #[test]
+    fn test_preview() {
+        assert_eq!(preview(""), "<empty>");
+        assert_eq!(preview(" \n\t "), "<empty>");
+        assert_eq!(
+            preview("A new version is available!\n  Run `upgrade`."),
+            "A new version is available! Run `upgrade`."
+        );
+        assert_eq!(
+            preview("warning: stale\neyJhbGciOiJSUzI1NiJ9.eyJhdWQiOiJidXBraXMifQ.c2ln"),
+            "warning: stale [REDACTED]"
+        );
+
+        let long = "word ".repeat(40);
+        let preview = preview(&long);
+        assert_eq!(preview.chars().count(), 81);
+        assert!(preview.ends_with('…'));
+    }
+}
@@ -0,0 +1,197 @@
+//! Token requests, and which providers can honor them.
+
+use std::{fmt, time::Duration};
+
+use crate::Claims;
+
+/// An environment that ambient ID tokens can be detected in.
+///
+/// This serializes as a lowercase, hyphenated name, e.g. `github-actions`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum Provider {
+    /// Google Cloud Platform.
+    Gcp,
+    /// GitHub Actions.
+    #[serde(rename = "github-actions")]
+    GitHubActions,
+    /// GitLab CI.
+    #[serde(rename = "gitlab-ci")]
+    GitLabCI,
+    /// Buildkite.
+    Buildkite,
+    /// CircleCI.
+    CircleCI,
+}
+
+impl Provider {
+    /// All providers, in detection order.
+    ///
+    /// More providers may be added in the future.
+    pub const ALL: &'static [Provider] = &[
+        Provider::Gcp,
+        Provider::GitHubActions,
+        Provider::GitLabCI,
+        Provider::Buildkite,
+        Provider::CircleCI,
+    ];
+
+    /// Returns whether this provider can honor the given part of a
+    /// [`TokenRequest`].
+    pub fn supports(self, feature: Feature) -> bool {
+        match feature {
+            Feature::Lifetime => matches!(self, Provider::Buildkite),
+            Feature::CustomClaims => matches!(self, Provider::CircleCI),
+        }
+    }
+}
+
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Provider::Gcp => "GCP",
+            Provider::GitHubActions => "GitHub Actions",
+            Provider::GitLabCI => "GitLab CI",
+            Provider::Buildkite => "Buildkite",
+            Provider::CircleCI => "CircleCI",
+        })
+    }
+}
+
+/// An optional part of a [`TokenRequest`], which not every
+/// [`Provider`] can honor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Feature {
+    /// A requested token lifetime.
+    Lifetime,
+    /// Custom claims, beyond the audience.
+    CustomClaims,
+}
+
+impl Feature {
+    /// Returns the providers that can honor this feature.
+    pub fn supported_by(self) -> Vec<Provider> {
+        Provider::ALL
+            .iter()
+            .copied()
+            .filter(|provider| provider.supports(self))
+            .collect()
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Feature::Lifetime => "token lifetimes",
+            Feature::CustomClaims => "custom claims",
+        })
+    }
+}
+
+/// A request for an ID token.
+///
+/// At minimum, a request has an audience. Any other parts of the
+/// request are only honored by some providers (see [`Provider::supports`]);
+/// requesting them elsewhere results in [`Error::Unsupported`](crate::Error::Unsupported).
+///
+/// Settings that only one provider understands aren't part of a request,
+/// and are configured on the [`Detector`](crate::Detector) instead. For
+/// example, the format of GCP tokens and whether they include license codes
+/// are set with [`GcpOptions`](crate::GcpOptions), since they only apply
+/// to tokens obtained directly from the metadata server.
+#[derive(Clone, Debug)]
+pub struct TokenRequest {
+    audience: String,
+    lifetime: Option<Duration>,
+    claims: Claims,
+}
+
+impl TokenRequest {
+    /// Creates a request for a token with the given audience.
+    pub fn new(audience: impl Into<String>) -> Self {
+        TokenRequest {
+            audience: audience.into(),
+            lifetime: None,
+            claims: Claims::new(),
+        }
+    }
+
+    /// Sets the requested lifetime of the token.
+    ///
+    /// Providers may round the lifetime down to whole seconds.
+    pub fn with_lifetime(mut self, lifetime: Duration) -> Self {
+        self.lifetime = Some(lifetime);
+        self
+    }
+
+    /// Sets the custom claims to request, replacing any already set.
+    pub fn with_claims(mut self, claims: Claims) -> Self {
+        self.claims = claims;
+        self
+    }
+
+    /// Adds a single custom claim to request.
+    pub fn with_claim(
+        mut self,
+        name: impl Into<String>,
+        value: impl Into<serde_json::Value>,
+    ) -> Self {
+        self.claims.insert(name.into(), value.into());
+        self
+    }
+
+    /// Returns the requested audience.
+    pub fn audience(&self) -> &str {
+        &self.audience
+    }
+
+    /// Returns the requested lifetime, if any.
+    pub fn lifetime(&self) -> Option<Duration> {
+        self.lifetime
+    }
+
+    /// Returns the requested custom claims.
+    pub fn claims(&self) -> &Claims {
+        &self.claims
+    }
+
+    /// Returns the optional features this request uses.
+    pub(crate) fn features(&self) -> impl Iterator<Item = Feature> {
+        [
+            self.lifetime.map(|_| Feature::Lifetime),
+            (!self.claims.is_empty()).then_some(Feature::CustomClaims),
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Feature, Provider, TokenRequest};
+
+    #[test]
+    fn test_features() {
+        let request = TokenRequest::new("bupkis");
+        assert_eq!(request.features().count(), 0);
+
+        let request = request.with_lifetime(Duration::from_secs(300));
+        assert_eq!(request.features().collect::<Vec<_>>(), [Feature::Lifetime]);
+
+        let request = request.with_claim("env", "prod");
+        assert_eq!(
+            request.features().collect::<Vec<_>>(),
+            [Feature::Lifetime, Feature::CustomClaims]
+        );
+    }
+
+    #[test]
+    fn test_supported_by() {
+        assert_eq!(Feature::Lifetime.supported_by(), [Provider::Buildkite]);
+        assert_eq!(Feature::CustomClaims.supported_by(), [Provider::CircleCI]);
+    }
+}